@@ -1,42 +1,138 @@
 extern crate alsa;
+extern crate regex;
 
 use anyhow::{ Result, Error };
 use alsa::seq;
+use alsa::poll::{ self, Descriptors, pollfd };
+use regex::Regex;
 use std::ffi::CString;
+use std::fmt;
+use std::time::Instant;
+use crate::drivers;
 
-#[derive(Debug)]
+/*
+ * A MIDI message, channel or system. Channels count from 0.
+ */
+#[derive(Debug, Clone, PartialEq)]
 pub enum Event {
+    // Note on. A velocity of 0 is also a note off.
     Note {
+        channel: u8,
         note: u8,
         velocity: u8
     },
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8
+    },
+    // Polyphonic aftertouch.
+    KeyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8
+    },
     Control {
+        channel: u8,
         param: u32,
         value: i32
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8
+    },
+    // Aftertouch for the whole channel.
+    ChannelPressure {
+        channel: u8,
+        pressure: u8
+    },
+    // From -8192 to 8191, centred on 0.
+    PitchBend {
+        channel: u8,
+        value: i16
+    },
+    // A whole message, from F0 to F7.
+    SysEx(Vec<u8>),
+    // A MIDI time code quarter frame, with the piece number in the top three bits.
+    QuarterFrame(u8),
+    // In sixteenth notes from the start of the song.
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset
+}
+
+/*
+ * Reads the data of an ALSA event, which should always be there for its type.
+ */
+fn event_data<D: seq::EventData>(ev: &seq::Event) -> Result<D> {
+    ev.get_data().ok_or_else(|| Error::msg(format!("No data in {:?} event", ev.get_type())))
+}
+
+/*
+ * Checks that a value from ALSA fits in a MIDI data byte, or in a channel number.
+ */
+fn data_byte(value: impl Into<i64>, max: u8, what: &str) -> Result<u8> {
+    let value = value.into();
+    if (0..=max as i64).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(Error::msg(format!("Invalid {} {}", what, value)))
     }
 }
 
 impl Event {
-    fn to_alsa_event(&self, port: i32, queue: i32) -> seq::Event {
+    /*
+     * The note and whether it is now down, for note on and note off alike.
+     */
+    pub fn note_state(&self) -> Option<(u8, bool)> {
+        match self {
+            Event::Note { note, velocity, .. } => Some((*note, *velocity > 0)),
+            Event::NoteOff { note, .. } => Some((*note, false)),
+            _ => None
+        }
+    }
+
+    fn to_alsa_event(&self, port: i32, queue: i32) -> seq::Event<'_> {
+        let note = |t, channel: u8, note: u8, velocity: u8| seq::Event::new(t, &seq::EvNote {
+            channel,
+            note,
+            velocity,
+            off_velocity: 0,
+            duration: 0
+        });
+        let ctrl = |t, channel: u8, param: u32, value: i32| seq::Event::new(t, &seq::EvCtrl {
+            channel,
+            param,
+            value
+        });
+        let queue_control = |t| seq::Event::new(t, &seq::EvQueueControl { queue, value: () });
         let mut ev = match self {
-            Event::Note { note, velocity } => {
-                let ev_note = seq::EvNote {
-                    channel: 0,
-                    note: *note,
-                    velocity: *velocity,
-                    off_velocity: 0,
-                    duration: 0
-                };
-                seq::Event::new(seq::EventType::Noteon, &ev_note)
-            }
-            Event::Control { param, value } => {
-                let ev_ctrl = seq::EvCtrl {
-                    channel: 0,
-                    param: *param,
-                    value: *value
-                };
-                seq::Event::new(seq::EventType::Controller, &ev_ctrl)
-            }
+            Event::Note { channel, note: n, velocity } => note(seq::EventType::Noteon, *channel, *n, *velocity),
+            Event::NoteOff { channel, note: n, velocity } => note(seq::EventType::Noteoff, *channel, *n, *velocity),
+            Event::KeyPressure { channel, note: n, pressure } => note(seq::EventType::Keypress, *channel, *n, *pressure),
+            Event::Control { channel, param, value } => ctrl(seq::EventType::Controller, *channel, *param, *value),
+            Event::ProgramChange { channel, program } =>
+                ctrl(seq::EventType::Pgmchange, *channel, 0, *program as i32),
+            Event::ChannelPressure { channel, pressure } =>
+                ctrl(seq::EventType::Chanpress, *channel, 0, *pressure as i32),
+            Event::PitchBend { channel, value } => ctrl(seq::EventType::Pitchbend, *channel, 0, *value as i32),
+            Event::SysEx(data) => seq::Event::new_ext(seq::EventType::Sysex, &data[..]),
+            Event::QuarterFrame(value) => ctrl(seq::EventType::Qframe, 0, 0, *value as i32),
+            Event::SongPosition(position) => ctrl(seq::EventType::Songpos, 0, 0, *position as i32),
+            Event::SongSelect(song) => ctrl(seq::EventType::Songsel, 0, 0, *song as i32),
+            Event::TuneRequest => seq::Event::new(seq::EventType::TuneRequest, &()),
+            Event::Clock => queue_control(seq::EventType::Clock),
+            Event::Start => queue_control(seq::EventType::Start),
+            Event::Continue => queue_control(seq::EventType::Continue),
+            Event::Stop => queue_control(seq::EventType::Stop),
+            Event::ActiveSensing => seq::Event::new(seq::EventType::Sensing, &()),
+            Event::Reset => seq::Event::new(seq::EventType::Reset, &())
         };
 
         ev.set_subs();
@@ -47,95 +143,301 @@ impl Event {
         ev
     }
 
-    fn from_alsa_event(ev: &seq::Event) -> Option<Event> {
-        match ev.get_type() {
-            seq::EventType::Noteon => {
-                let ev_note = ev.get_data::<seq::EvNote>().unwrap();
-                Some(Event::Note {
-                    note: ev_note.note,
-                    velocity: ev_note.velocity
-                })
+    /*
+     * The MIDI message in an ALSA event, or None for events that aren't MIDI messages, such as
+     * ports coming and going. Fails if the event is malformed.
+     */
+    fn from_alsa_event(ev: &seq::Event) -> Result<Option<Event>> {
+        let event = match ev.get_type() {
+            seq::EventType::Noteon | seq::EventType::Noteoff | seq::EventType::Keypress => {
+                let ev_note: seq::EvNote = event_data(ev)?;
+                let channel = data_byte(ev_note.channel, 15, "channel")?;
+                let note = data_byte(ev_note.note, 127, "note")?;
+                let velocity = data_byte(ev_note.velocity, 127, "velocity")?;
+                match ev.get_type() {
+                    seq::EventType::Noteon => Event::Note { channel, note, velocity },
+                    seq::EventType::Noteoff => Event::NoteOff { channel, note, velocity },
+                    _ => Event::KeyPressure { channel, note, pressure: velocity }
+                }
             },
             seq::EventType::Controller => {
-                let ev_ctrl = ev.get_data::<seq::EvCtrl>().unwrap();
-                Some(Event::Control {
-                    param: ev_ctrl.param,
-                    value: ev_ctrl.value
-                })
-            }
-            _ => None
-        }
+                let ev_ctrl: seq::EvCtrl = event_data(ev)?;
+                Event::Control {
+                    channel: data_byte(ev_ctrl.channel, 15, "channel")?,
+                    param: data_byte(ev_ctrl.param, 127, "controller")? as u32,
+                    value: data_byte(ev_ctrl.value, 127, "controller value")? as i32
+                }
+            },
+            seq::EventType::Pgmchange => {
+                let ev_ctrl: seq::EvCtrl = event_data(ev)?;
+                Event::ProgramChange {
+                    channel: data_byte(ev_ctrl.channel, 15, "channel")?,
+                    program: data_byte(ev_ctrl.value, 127, "program")?
+                }
+            },
+            seq::EventType::Chanpress => {
+                let ev_ctrl: seq::EvCtrl = event_data(ev)?;
+                Event::ChannelPressure {
+                    channel: data_byte(ev_ctrl.channel, 15, "channel")?,
+                    pressure: data_byte(ev_ctrl.value, 127, "pressure")?
+                }
+            },
+            seq::EventType::Pitchbend => {
+                let ev_ctrl: seq::EvCtrl = event_data(ev)?;
+                if !(-8192..8192).contains(&ev_ctrl.value) {
+                    return Err(Error::msg(format!("Invalid pitch bend {}", ev_ctrl.value)));
+                }
+                Event::PitchBend {
+                    channel: data_byte(ev_ctrl.channel, 15, "channel")?,
+                    value: ev_ctrl.value as i16
+                }
+            },
+            seq::EventType::Sysex => {
+                let data = ev.get_ext().ok_or(Error::msg("No data in SysEx event"))?;
+                Event::SysEx(data.to_vec())
+            },
+            seq::EventType::Qframe => {
+                let ev_ctrl: seq::EvCtrl = event_data(ev)?;
+                Event::QuarterFrame(data_byte(ev_ctrl.value, 127, "quarter frame")?)
+            },
+            seq::EventType::Songpos => {
+                let ev_ctrl: seq::EvCtrl = event_data(ev)?;
+                if !(0..0x4000).contains(&ev_ctrl.value) {
+                    return Err(Error::msg(format!("Invalid song position {}", ev_ctrl.value)));
+                }
+                Event::SongPosition(ev_ctrl.value as u16)
+            },
+            seq::EventType::Songsel => {
+                let ev_ctrl: seq::EvCtrl = event_data(ev)?;
+                Event::SongSelect(data_byte(ev_ctrl.value, 127, "song")?)
+            },
+            seq::EventType::TuneRequest => Event::TuneRequest,
+            seq::EventType::Clock => Event::Clock,
+            seq::EventType::Start => Event::Start,
+            seq::EventType::Continue => Event::Continue,
+            seq::EventType::Stop => Event::Stop,
+            seq::EventType::Sensing => Event::ActiveSensing,
+            seq::EventType::Reset => Event::Reset,
+            _ => return Ok(None)
+        };
+        Ok(Some(event))
     }
 }
 
 pub trait PadControl {
     fn process_out(&mut self) -> Result<Vec<Event>>;
     fn process_in(&mut self, events: Vec<Event>) -> Result<()>;
+    // Descriptors that become readable when process_out has something to return.
+    fn poll_descriptors(&self) -> Result<Vec<pollfd>>;
+    // Whether there is a pad to talk to. This can change in process_out as pads come and go.
+    fn connected(&self) -> bool {
+        true
+    }
 }
 
+/*
+ * Talks to the pad through the ALSA sequencer. Ports that might be a Launchpad are sent a
+ * Device Inquiry, and the first one that answers as a Launchpad becomes the pad. Only its
+ * input is passed on. When the pad goes away, new ports are probed until one answers again.
+ */
 pub struct AlsaSeq {
     seq: seq::Seq,
     port: seq::Addr,
     queue: i32,
+    // The pad, once a port has answered.
+    device: Option<seq::Addr>,
+    // Ports that have been sent the inquiry but haven't answered yet, and when they were sent it.
+    candidates: Vec<(seq::Addr, Instant)>,
+    matcher: DeviceMatch
+}
+
+/*
+ * Which ports may be the pad. The names are searched for anywhere in the client and port names.
+ * With nothing set, clients with DEFAULT_CLIENT in their name are tried, or every port with any.
+ */
+#[derive(Default)]
+pub struct DeviceMatch {
+    pub client: Option<Regex>,
+    pub port: Option<Regex>,
+    pub address: Option<seq::Addr>,
+    pub any: bool
 }
 
+pub const DEFAULT_CLIENT: &str = "Launchpad";
+
+// Our own sequencer clients are called this, or this and a space and the name of a MIDI plugin.
+const CLIENT_NAME: &str = "Blinkenpad";
+
+fn is_ours(client_name: &str) -> bool {
+    client_name.strip_prefix(CLIENT_NAME).is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+}
+
+impl DeviceMatch {
+    /*
+     * Reads an address given as "client:port", e.g. "24:0".
+     */
+    pub fn parse_address(text: &str) -> Result<seq::Addr> {
+        let (client, port) = text.split_once(':')
+            .ok_or_else(|| Error::msg(format!("Address {} is not client:port", text)))?;
+        Ok(seq::Addr {
+            client: client.trim().parse().map_err(|_| Error::msg(format!("Bad client in address {}", text)))?,
+            port: port.trim().parse().map_err(|_| Error::msg(format!("Bad port in address {}", text)))?
+        })
+    }
+
+    fn is_set(&self) -> bool {
+        self.client.is_some() || self.port.is_some() || self.address.is_some()
+    }
+
+    fn matches(&self, client_name: &str, port_name: &str, addr: seq::Addr) -> bool {
+        if !self.is_set() && !self.any {
+            return client_name.contains(DEFAULT_CLIENT);
+        }
+        self.client.as_ref().is_none_or(|r| r.is_match(client_name))
+            && self.port.as_ref().is_none_or(|r| r.is_match(port_name))
+            && self.address.is_none_or(|a| a == addr)
+    }
+}
+
+impl fmt::Display for DeviceMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.any {
+            return write!(f, "any port");
+        }
+        if !self.is_set() {
+            return write!(f, "client /{}/", DEFAULT_CLIENT);
+        }
+        let mut parts = Vec::new();
+        if let Some(client) = &self.client {
+            parts.push(format!("client /{}/", client));
+        }
+        if let Some(port) = &self.port {
+            parts.push(format!("port /{}/", port));
+        }
+        if let Some(addr) = &self.address {
+            parts.push(format!("address {}:{}", addr.client, addr.port));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+// Launchpads that don't answer the inquiry are found by this client name, as a Mini Mk1, unless
+// the ports to use were given.
+const FALLBACK_NAME: &str = "Launchpad Mini";
+
 impl PadControl for AlsaSeq {
 
     fn process_in(&mut self, events: Vec<Event>) -> Result<()> {
-        for event in events {
-            self.seq.event_output(&mut event.to_alsa_event(self.port.port, self.queue))?;
+        let written = events.into_iter()
+            .try_for_each(|event| self.seq.event_output(&mut event.to_alsa_event(self.port.port, self.queue)).map(|_| ()))
+            .and_then(|_| self.seq.drain_output().map(|_| ()));
+        // A pad that can't be written to is treated as gone, and asked again whether it is there.
+        if let (Err(e), Some(device)) = (written, self.device) {
+            eprintln!("Writing to Launchpad {}:{} failed: {}", device.client, device.port, e);
+            let _ = self.seq.drop_output();
+            self.forget_device();
+            self.probe_addr(device);
         }
-
-        self.seq.drain_output()?;
         Ok(())
     }
 
     fn process_out(&mut self) -> Result<Vec<Event>> {
-        let mut input = self.seq.input();
+        let mut incoming = Vec::new();
+        let mut started = Vec::new();
+        let mut exited = Vec::new();
+        {
+            let mut input = self.seq.input();
+            while input.event_input_pending(true)? != 0 {
+                let event = input.event_input()?;
+                match Event::from_alsa_event(&event) {
+                    Ok(Some(ev)) => incoming.push((event.get_source(), ev)),
+                    Err(e) => {
+                        let source = event.get_source();
+                        eprintln!("Dropped a message from {}:{}: {}", source.client, source.port, e);
+                    },
+                    Ok(None) => {
+                        let addr: Option<seq::Addr> = event.get_data();
+                        match (event.get_type(), addr) {
+                            (seq::EventType::PortStart, Some(addr)) => started.push(addr),
+                            (seq::EventType::PortExit, Some(addr)) => exited.push(addr),
+                            // The whole client went, so all of its ports did.
+                            (seq::EventType::ClientExit, Some(addr)) =>
+                                exited.push(seq::Addr { client: addr.client, port: -1 }),
+                            (seq::EventType::PortStart | seq::EventType::PortExit | seq::EventType::ClientExit, None) =>
+                                eprintln!("Dropped an announcement without an address"),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+
+        for addr in exited {
+            self.port_exited(addr);
+        }
         let mut r_vec = Vec::new();
-        while input.event_input_pending(true)? != 0 {
-            let event = input.event_input()?;
-            match Event::from_alsa_event(&event) {
-                Some(ev) => r_vec.push(ev),
-                None => {
-                    match event.get_type() {
-                        seq::EventType::PortStart => {
-                            let addr: seq::Addr = event.get_data().ok_or(Error::msg("no address"))?;
-                            let info = self.seq.get_any_client_info(addr.client)?;
-                            self.find_launchpad(&info)?;
+        for (source, ev) in incoming {
+            match ev {
+                Event::SysEx(data) if self.is_candidate(source) => {
+                    if self.device.is_none() && drivers::driver_for(&data).is_some() {
+                        match self.identify(source) {
+                            Ok(()) => r_vec.push(Event::SysEx(data)),
+                            Err(e) => {
+                                eprintln!("Couldn't use the Launchpad at {}:{}: {:#}", source.client, source.port, e);
+                                self.drop_candidate(source);
+                            }
                         }
-                        _ => {}
+                    } else {
+                        self.drop_candidate(source);
                     }
+                },
+                ev => if self.device == Some(source) {
+                    r_vec.push(ev);
                 }
             }
         }
+        for addr in started {
+            self.probe_addr(addr);
+        }
+        self.expire_candidates();
         Ok(r_vec)
     }
 
+    fn poll_descriptors(&self) -> Result<Vec<pollfd>> {
+        Ok((&self.seq, Some(alsa::Direction::Capture)).get()?)
+    }
+
+    fn connected(&self) -> bool {
+        self.device.is_some()
+    }
+
 }
 
 impl AlsaSeq {
 
-    fn create_port_info() -> Result<seq::PortInfo> {
+    fn create_port_info(name: &str) -> Result<seq::PortInfo> {
         let mut dinfo = seq::PortInfo::empty()?;
         dinfo.set_capability(seq::PortCap::WRITE | seq::PortCap::SUBS_WRITE |
             seq::PortCap::READ | seq::PortCap::SUBS_READ);
         dinfo.set_type(seq::PortType::MIDI_GENERIC | seq::PortType::APPLICATION);
-        dinfo.set_name(&CString::new("Blinkenport")?);
+        dinfo.set_name(&CString::new(name)?);
         Ok(dinfo)
     }
 
-    pub fn setup_alsaseq() -> Result<AlsaSeq> {
+    pub fn setup_alsaseq(matcher: DeviceMatch) -> Result<AlsaSeq> {
         let seq = seq::Seq::open(None, None, true)?;
-        seq.set_client_name(&CString::new("Blinkenpad")?)?;
+        seq.set_client_name(&CString::new(CLIENT_NAME)?)?;
 
-        let port_info = AlsaSeq::create_port_info()?;
+        let port_info = AlsaSeq::create_port_info("Blinkenport")?;
         seq.create_port(&port_info)?;
         let queue = seq.alloc_queue()?;
         Ok(AlsaSeq {
             seq: seq,
             port: port_info.addr(),
             queue: queue,
+            device: None,
+            candidates: Vec::new(),
+            matcher
         })
     }
 
@@ -144,7 +446,7 @@ impl AlsaSeq {
         while input.event_input_pending(true)? != 0 {
             input.drop_input()?;
         }
-        println!("Dropped");
+        eprintln!("Dropped");
         Ok(())
     }
 
@@ -152,7 +454,7 @@ impl AlsaSeq {
             let subs = seq::PortSubscribe::empty()?;
             subs.set_sender(port.addr());
             subs.set_dest(self.port);
-            println!("Input port {}, {}", port.get_client(), port.get_port());
+            eprintln!("Input port {}, {}", port.get_client(), port.get_port());
             self.seq.subscribe_port(&subs)?;
             Ok(())
     }
@@ -161,40 +463,302 @@ impl AlsaSeq {
             let subs = seq::PortSubscribe::empty()?;
             subs.set_sender(self.port);
             subs.set_dest(port.addr());
-            println!("Output port {}, {}", port.get_client(), port.get_port());
+            eprintln!("Output port {}, {}", port.get_client(), port.get_port());
             self.seq.subscribe_port(&subs)?;
             Ok(())
     }
 
-    fn connect_ports(self: &AlsaSeq, client: &seq::ClientInfo) -> Result<()> {
-        for from_port in seq::PortIter::new(&self.seq, client.get_client()) {
-            if from_port.get_capability().contains(seq::PortCap::NO_EXPORT) {
-                println!("Skipping connection to unroutable port.");
-                return Ok(());
-            }
-            if from_port.get_capability().contains(seq::PortCap::SUBS_READ) {
-                self.connect_input(&from_port)?;
-            }
-            if from_port.get_capability().contains(seq::PortCap::SUBS_WRITE) {
-                self.connect_output(&from_port)?;
+    /*
+     * Listens for ports coming and going, which the system announces.
+     */
+    fn connect_announce(&self) -> Result<()> {
+        let subs = seq::PortSubscribe::empty()?;
+        subs.set_sender(seq::Addr::system_announce());
+        subs.set_dest(self.port);
+        self.seq.subscribe_port(&subs)?;
+        Ok(())
+    }
+
+    /*
+     * Sends the Device Inquiry to a port that could be a pad, and listens for the answer.
+     * Returns whether it was sent.
+     */
+    fn probe_port(&mut self, port: &seq::PortInfo) -> Result<bool> {
+        let caps = port.get_capability();
+        if port.get_client() == seq::Addr::system_announce().client
+            || caps.contains(seq::PortCap::NO_EXPORT)
+            || !caps.contains(seq::PortCap::SUBS_READ | seq::PortCap::SUBS_WRITE) {
+            return Ok(false);
+        }
+        let client_info = self.seq.get_any_client_info(port.get_client())?;
+        let client_name = client_info.get_name()?;
+        // Neither this client nor the ports of MIDI plugins are ever a pad.
+        if is_ours(client_name) || !self.matcher.matches(client_name, port.get_name()?, port.addr()) {
+            return Ok(false);
+        }
+        self.connect_input(port)?;
+        self.candidates.push((port.addr(), Instant::now()));
+        let mut ev = seq::Event::new_ext(seq::EventType::Sysex, &drivers::DEVICE_INQUIRY[..]);
+        ev.set_source(self.port.port);
+        ev.set_dest(port.addr());
+        ev.set_direct();
+        self.seq.event_output_direct(&mut ev)?;
+        Ok(true)
+    }
+
+    /*
+     * Probes a port, logging rather than returning anything that goes wrong, as one port that
+     * misbehaves shouldn't stop the search. Returns whether it was sent the inquiry.
+     */
+    fn try_probe(&mut self, port: &seq::PortInfo) -> bool {
+        match self.probe_port(port) {
+            Ok(sent) => sent,
+            Err(e) => {
+                eprintln!("Couldn't probe {}:{}: {:#}", port.get_client(), port.get_port(), e);
+                self.drop_candidate(port.addr());
+                false
             }
         }
-        Ok(())
     }
 
-    fn find_launchpad(self: &AlsaSeq, info: &seq::ClientInfo) -> Result<()> {
-        let name = info.get_name()?;
-        if name == "Launchpad Mini" || name == "System" {
-            println!("Found Launchpad: {}", name);
-            self.connect_ports(info)?;
+    fn probe_addr(&mut self, addr: seq::Addr) {
+        // It may already have gone again.
+        if let Ok(info) = self.seq.get_any_port_info(addr) {
+            self.try_probe(&info);
         }
+    }
+
+    fn is_candidate(&self, addr: seq::Addr) -> bool {
+        self.candidates.iter().any(|(c, _)| *c == addr)
+    }
+
+    fn identify(&mut self, addr: seq::Addr) -> Result<()> {
+        eprintln!("Found Launchpad: {}", self.seq.get_any_client_info(addr.client)?.get_name()?);
+        self.use_device(addr)
+    }
+
+    /*
+     * Makes a candidate the pad. Its input is connected already, so this connects its output.
+     */
+    fn use_device(&mut self, addr: seq::Addr) -> Result<()> {
+        let info = self.seq.get_any_port_info(addr)?;
+        self.connect_output(&info)?;
+        self.candidates.retain(|(c, _)| *c != addr);
+        self.device = Some(addr);
         Ok(())
     }
 
-    pub fn connect_all(self: &AlsaSeq) -> Result<()> {
+    fn drop_candidate(&mut self, addr: seq::Addr) {
+        if !self.is_candidate(addr) {
+            return;
+        }
+        self.candidates.retain(|(c, _)| *c != addr);
+        if let Err(e) = self.seq.unsubscribe_port(addr, self.port) {
+            eprintln!("Couldn't disconnect from {}:{}: {}", addr.client, addr.port, e);
+        }
+    }
+
+    /*
+     * Gives up on ports that haven't answered the inquiry in time. If there is no pad yet, one
+     * of them may still be a Launchpad going by its name, or because it was asked for.
+     */
+    fn expire_candidates(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<seq::Addr> = self.candidates.iter()
+            .filter(|(_, sent)| now.duration_since(*sent) >= drivers::INQUIRY_TIMEOUT)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in expired {
+            let name = self.seq.get_any_client_info(addr.client).and_then(|c| c.get_name().map(String::from));
+            if self.device.is_none() && (self.matcher.is_set() || name.is_ok_and(|n| n == FALLBACK_NAME)) {
+                eprintln!("Using {}:{} as a Launchpad Mini", addr.client, addr.port);
+                if let Err(e) = self.use_device(addr) {
+                    eprintln!("Couldn't use {}:{}: {:#}", addr.client, addr.port, e);
+                    self.drop_candidate(addr);
+                }
+            } else {
+                self.drop_candidate(addr);
+            }
+        }
+    }
+
+    /*
+     * Forgets a port that has gone away, or every port of a client for port -1. Losing the pad
+     * leaves nothing connected until another one answers.
+     */
+    fn port_exited(&mut self, addr: seq::Addr) {
+        let gone = |a: &seq::Addr| a.client == addr.client && (addr.port < 0 || a.port == addr.port);
+        self.candidates.retain(|(c, _)| !gone(c));
+        if self.device.is_some_and(|d| gone(&d)) {
+            self.forget_device();
+        }
+    }
+
+    fn forget_device(&mut self) {
+        if let Some(device) = self.device.take() {
+            eprintln!("Lost Launchpad {}:{}", device.client, device.port);
+            // The subscriptions normally go with the port, so failing here is expected.
+            let _ = self.seq.unsubscribe_port(device, self.port);
+            let _ = self.seq.unsubscribe_port(self.port, device);
+        }
+    }
+
+    /*
+     * Finds the pad, waiting a moment for it to answer. Returns its answer to the inquiry, or
+     * None if nothing answered, in which case a port may have been taken anyway and is assumed
+     * to be a Mini Mk1.
+     */
+    pub fn connect_all(&mut self) -> Result<Option<Vec<u8>>> {
+        self.connect_announce()?;
+        let mut ports = Vec::new();
         for from_info in seq::ClientIter::new(&self.seq) {
-            self.find_launchpad(&from_info)?;
+            ports.extend(seq::PortIter::new(&self.seq, from_info.get_client()));
+        }
+        let mut probed = 0;
+        for port in &ports {
+            if self.try_probe(port) {
+                probed += 1;
+            }
+        }
+        if probed == 0 {
+            eprintln!("No MIDI ports match {}", self.matcher);
+        }
+
+        // Candidates expire in process_out, falling back on the name if nothing answered.
+        while !self.candidates.is_empty() {
+            let mut fds = self.poll_descriptors()?;
+            poll::poll(&mut fds, 100)?;
+            for ev in self.process_out()? {
+                if let Event::SysEx(data) = ev {
+                    return Ok(Some(data));
+                }
+            }
+        }
+        if self.device.is_none() {
+            eprintln!("No Launchpad found among {} ports matching {}; waiting for one", probed, self.matcher);
         }
+        Ok(None)
+    }
+}
+
+/*
+ * A sequencer port of our own for other applications, e.g. a DAW, to connect to. What is sent
+ * goes to everything subscribed to it, and whatever they send to it can be received.
+ */
+pub struct MidiPort {
+    seq: seq::Seq,
+    port: seq::Addr,
+    queue: i32
+}
+
+impl MidiPort {
+    pub fn open(name: &str) -> Result<MidiPort> {
+        let seq = seq::Seq::open(None, None, true)?;
+        seq.set_client_name(&CString::new(format!("{} {}", CLIENT_NAME, name))?)?;
+
+        let port_info = AlsaSeq::create_port_info(name)?;
+        seq.create_port(&port_info)?;
+        let queue = seq.alloc_queue()?;
+        Ok(MidiPort {
+            seq,
+            port: port_info.addr(),
+            queue
+        })
+    }
+
+    pub fn send(&self, events: &[Event]) -> Result<()> {
+        for event in events {
+            self.seq.event_output(&mut event.to_alsa_event(self.port.port, self.queue))?;
+        }
+        self.seq.drain_output()?;
         Ok(())
     }
+
+    pub fn receive(&self) -> Result<Vec<Event>> {
+        let mut result = Vec::new();
+        let mut input = self.seq.input();
+        while input.event_input_pending(true)? != 0 {
+            match Event::from_alsa_event(&input.event_input()?) {
+                Ok(event) => result.extend(event),
+                Err(e) => eprintln!("Dropped a message on {}:{}: {}", self.port.client, self.port.port, e)
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn poll_descriptors(&self) -> Result<Vec<pollfd>> {
+        Ok((&self.seq, Some(alsa::Direction::Capture)).get()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(client: i32, port: i32) -> seq::Addr {
+        seq::Addr { client, port }
+    }
+
+    #[test]
+    fn parse_address() {
+        assert_eq!(DeviceMatch::parse_address("24:0").unwrap(), addr(24, 0));
+        assert_eq!(DeviceMatch::parse_address(" 128 : 3 ").unwrap(), addr(128, 3));
+        assert!(DeviceMatch::parse_address("24").is_err());
+        assert!(DeviceMatch::parse_address(":0").is_err());
+        assert!(DeviceMatch::parse_address("24:").is_err());
+        assert!(DeviceMatch::parse_address("a:0").is_err());
+        assert!(DeviceMatch::parse_address("24:0:1").is_err());
+    }
+
+    #[test]
+    fn default_match_is_launchpad_clients() {
+        let matcher = DeviceMatch::default();
+        assert!(matcher.matches("Launchpad Mini", "Launchpad Mini MIDI 1", addr(24, 0)));
+        assert!(matcher.matches("Launchpad X", "Launchpad X LPX MIDI In", addr(28, 1)));
+        // Only the client name counts.
+        assert!(!matcher.matches("Midi Through", "Launchpad", addr(14, 0)));
+        assert!(!matcher.matches("launchpad", "MIDI 1", addr(24, 0)));
+        assert_eq!(matcher.to_string(), "client /Launchpad/");
+    }
+
+    #[test]
+    fn any_matches_every_port() {
+        let matcher = DeviceMatch { any: true, ..DeviceMatch::default() };
+        assert!(matcher.matches("Launchpad Mini", "Launchpad Mini MIDI 1", addr(24, 0)));
+        assert!(matcher.matches("USB MIDI Bridge", "Port 1", addr(20, 1)));
+        assert!(matcher.matches("Midi Through", "Midi Through Port-0", addr(14, 0)));
+        assert_eq!(matcher.to_string(), "any port");
+    }
+
+    #[test]
+    fn every_given_pattern_has_to_match() {
+        let matcher = DeviceMatch {
+            client: Some(Regex::new("^Bridge").unwrap()),
+            port: Some(Regex::new("2$").unwrap()),
+            address: None,
+            any: false
+        };
+        assert!(matcher.matches("Bridge A", "Port 2", addr(20, 1)));
+        assert!(!matcher.matches("Bridge A", "Port 1", addr(20, 0)));
+        assert!(!matcher.matches("My Bridge", "Port 2", addr(20, 1)));
+        // A pattern replaces the default rather than narrowing it.
+        assert!(!matcher.matches("Launchpad Mini", "Launchpad Mini MIDI 1", addr(24, 0)));
+        assert_eq!(matcher.to_string(), "client /^Bridge/, port /2$/");
+
+        let matcher = DeviceMatch { address: Some(addr(24, 0)), ..DeviceMatch::default() };
+        assert!(matcher.matches("Anything", "At all", addr(24, 0)));
+        assert!(!matcher.matches("Launchpad Mini", "Launchpad Mini MIDI 1", addr(24, 1)));
+        assert_eq!(matcher.to_string(), "address 24:0");
+    }
+
+    #[test]
+    fn our_own_clients() {
+        assert!(is_ours("Blinkenpad"));
+        assert!(is_ours("Blinkenpad MIDI"));
+        assert!(is_ours("Blinkenpad Drums"));
+        assert!(!is_ours("Blinkenpads"));
+        assert!(!is_ours("Not Blinkenpad"));
+        assert!(!is_ours("Launchpad Mini"));
+        assert!(!is_ours(""));
+    }
 }
@@ -1,7 +1,14 @@
 
 use crate::launchpad;
-use launchpad::{ PadColour, LaunchPadMini, PadLocation, PadArea};
+use crate::config::{ Config, Background, InputConfig, PluginConfig };
+use crate::events::{ PadEvent, Gestures };
+use crate::record::Recorder;
+use crate::midi_plugin::MidiPorts;
+use launchpad::{ PadColour, PadLocation, PadArea};
 use anyhow::{ Result, Error, Context };
+use alsa::poll::pollfd;
+use std::any::Any;
+use std::panic::{ self, AssertUnwindSafe };
 
 /*
  * This keeps the current state of an area on the pad, and will pass those onto the actual IO.
@@ -21,12 +28,40 @@ impl PadMirror {
         }
     }
 
-    fn minimise(&self, set_values: Vec<(PadLocation, PadColour)>) -> Vec<(PadLocation, PadColour)> {
-        set_values.into_iter().filter(|(loc, col)| match loc {
-            PadLocation::OnPad(x,y) => self.pad[(*x+*y*8) as usize] != *col,
-            PadLocation::Letters(l) => self.letters[*l as usize] != *col,
-            PadLocation::Numbers(n) => self.numbers[*n as usize] != *col
-        }).collect()
+    /*
+     * The changes needed to turn this mirror into the given frame.
+     */
+    fn diff(&self, frame: &PadMirror) -> Vec<(PadLocation, PadColour)> {
+        let mut result = Vec::new();
+        for i in 0..64u8 {
+            if self.pad[i as usize] != frame.pad[i as usize] {
+                result.push((PadLocation::on_pad(i % 8, i / 8), frame.pad[i as usize]));
+            }
+        }
+        for i in 0..8u8 {
+            if self.letters[i as usize] != frame.letters[i as usize] {
+                result.push((PadLocation::letter(i), frame.letters[i as usize]));
+            }
+            if self.numbers[i as usize] != frame.numbers[i as usize] {
+                result.push((PadLocation::number(i), frame.numbers[i as usize]));
+            }
+        }
+        result
+    }
+
+    /*
+     * Every cell, for repainting the whole pad.
+     */
+    fn cells(&self) -> Vec<(PadLocation, PadColour)> {
+        let mut result = Vec::new();
+        for i in 0..64u8 {
+            result.push((PadLocation::on_pad(i % 8, i / 8), self.pad[i as usize]));
+        }
+        for i in 0..8u8 {
+            result.push((PadLocation::letter(i), self.letters[i as usize]));
+            result.push((PadLocation::number(i), self.numbers[i as usize]));
+        }
+        result
     }
 
     fn clear(&mut self) {
@@ -66,8 +101,14 @@ impl PadLoopback {
 }
 
 impl PluginArea for PadLoopback {
-    fn process_input(&mut self, _tick: u32, set_values: &Vec<PadLocation>) -> Result<()> {
-        self.locations = set_values.clone();
+    fn process_input(&mut self, _tick: u32, events: &Vec<PadEvent>) -> Result<()> {
+        for event in events {
+            match event {
+                PadEvent::Press(l) => self.locations.push(l.clone()),
+                PadEvent::Release(l, _) => self.locations.retain(|x| x != l),
+                _ => {}
+            }
+        }
         Ok(())
     }
     fn process_output(&mut self, _tick: u32) -> Result<Vec<(PadLocation, PadColour)>> {
@@ -75,26 +116,124 @@ impl PluginArea for PadLoopback {
     }
 }
 
+/*
+ * For plugins that only have cells on the grid. Presses on the Letters or Numbers never reach
+ * them, so one that does is an error; any other event is for them to ignore.
+ */
+pub fn grid_only(event: &PadEvent) -> Result<()> {
+    match event {
+        PadEvent::Press(PadLocation::Letters(_)) => Err(Error::msg("Invalid letter pad press in plugin")),
+        PadEvent::Press(PadLocation::Numbers(_)) => Err(Error::msg("Invalid number pad press in plugin")),
+        _ => Ok(())
+    }
+}
+
 pub trait PluginArea {
-    fn process_input(&mut self, tick: u32, set_values: &Vec<PadLocation>) -> Result<()>;
+    fn process_input(&mut self, tick: u32, events: &Vec<PadEvent>) -> Result<()>;
     fn process_output(&mut self, tick: u32) -> Result<Vec<(PadLocation, PadColour)>>;
+
+    /*
+     * Descriptors that should wake the main loop when they become readable, so that changes
+     * show up without waiting for the next tick.
+     */
+    fn poll_descriptors(&self) -> Result<Vec<pollfd>> {
+        Ok(Vec::new())
+    }
+
+    /*
+     * Called once the plugin has been placed, with the size of its area, before any input or
+     * output. An error stops the layout from loading.
+     */
+    fn init(&mut self, _width: u8, _height: u8) -> Result<()> {
+        Ok(())
+    }
+
+    /*
+     * Called when the plugin's page is shown or hidden. Plugins start out hidden. Hidden plugins
+     * get no input, and their output is only asked for if the page runs in the background.
+     */
+    fn on_show(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_hide(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /*
+     * Called when the pad turns up or goes away. Buttons that were down when the pad went away
+     * will never be released.
+     */
+    fn on_device_connected(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_device_lost(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /*
+     * Called before the plugin is dropped, on exit or when the layout is reloaded.
+     */
+    fn shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /*
+     * Offers the layout replacing this one anything it should keep rather than open again.
+     * Called while reloading, before the new plugins are built.
+     */
+    fn hand_over(&self, _ports: &mut MidiPorts) {
+    }
+
+    /*
+     * Lights cells, or turns them off with None, for a client of the control socket. Only
+     * plugins that keep their area for that take them. Locations are inside the plugin's area.
+     */
+    fn set_cells(&mut self, _cells: &[(PadLocation, Option<PadColour>)]) -> Result<()> {
+        Err(Error::msg("Plugin doesn't take cells"))
+    }
 }
 
+/*
+ * Places a plugin area on the pad. If it claims letters, the Letters buttons on its rows are
+ * routed to it as well, numbered from its top row.
+ */
 struct PadPlugin<'a> {
+    name: Option<String>,
     x: u8,
     y: u8,
     width: u8,
     height: u8,
-    area: Box<dyn PluginArea + 'a>
+    letters: bool,
+    area: Box<dyn PluginArea + 'a>,
+    fault: Option<Fault>,
+    // Disabled plugins get no input and light nothing, as if their page were suspended.
+    enabled: bool
 }
 
+/*
+ * A plugin that returned an error or panicked. It gets no input and shows an error pattern
+ * until it next succeeds; retries back off from a second up to a minute.
+ */
+struct Fault {
+    attempts: u32,
+    retry_at: u32
+}
+
+const FAULT_RETRY_TICKS: u32 = 10;
+const FAULT_MAX_RETRY_TICKS: u32 = 600;
+
 impl PluginArea for PadPlugin<'_> {
-    fn process_input(&mut self, tick: u32, set_values: &Vec<PadLocation>) -> Result<()> {
-        self.area.process_input(tick,
-            &set_values.into_iter()
-            .filter_map(|loc| self.translate(&loc))
-            .collect()
-        ) 
+    fn process_input(&mut self, tick: u32, events: &Vec<PadEvent>) -> Result<()> {
+        let events: Vec<PadEvent> = events.iter()
+            .filter_map(|ev| self.translate(ev.location()).map(|loc| ev.with_location(loc)))
+            .collect();
+        self.area.process_input(tick, &events)
+    }
+
+    fn poll_descriptors(&self) -> Result<Vec<pollfd>> {
+        self.area.poll_descriptors()
     }
 
     fn process_output(&mut self, tick: u32) -> Result<Vec<(PadLocation, PadColour)>> {
@@ -107,19 +246,139 @@ impl PluginArea for PadPlugin<'_> {
                 } else {
                     Err(Error::msg("Outside valid area"))
                 },
-                PadLocation::Letters(_) => Err(Error::msg("Not on Pad")),
+                // Plugins may light letters whether or not they were given them.
+                PadLocation::Letters(_) if !self.letters => continue,
+                PadLocation::Letters(l) => if l < self.height {
+                    Ok(PadLocation::Letters(l + self.y))
+                } else {
+                    Err(Error::msg("Letter outside valid area"))
+                },
                 PadLocation::Numbers(_) => Err(Error::msg("Not on Pad"))
             }?;
             result.push((l2, c));
         }
         Ok(result)
     }
+
+    // Cells are checked against the plugin's area before it sees them.
+    fn set_cells(&mut self, cells: &[(PadLocation, Option<PadColour>)]) -> Result<()> {
+        for (loc, _) in cells {
+            let inside = match loc {
+                PadLocation::OnPad(x, y) => *x < self.width && *y < self.height,
+                PadLocation::Letters(l) => self.letters && *l < self.height,
+                PadLocation::Numbers(_) => false
+            };
+            if !inside {
+                return Err(Error::msg(format!("Cell {} is outside the plugin", loc)));
+            }
+        }
+        self.area.set_cells(cells)
+    }
 }
 
 impl PadPlugin<'_> {
+    /*
+     * Runs a call into the plugin, turning errors and panics into a fault.
+     */
+    fn guard<T, F>(&mut self, tick: u32, f: F) -> Option<T>
+        where F: FnOnce(&mut Self) -> Result<T> {
+        let error = match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(Ok(value)) => {
+                if self.fault.take().is_some() {
+                    eprintln!("Plugin at {},{} recovered", self.x, self.y);
+                }
+                return Some(value);
+            },
+            Ok(Err(e)) => format!("{:#}", e),
+            Err(p) => PadPlugin::panic_message(p)
+        };
+        let attempts = self.fault.as_ref().map_or(0, |f| f.attempts + 1);
+        let backoff = FAULT_RETRY_TICKS.saturating_mul(1 << attempts.min(16)).min(FAULT_MAX_RETRY_TICKS);
+        eprintln!("Plugin at {},{} failed: {}; retrying in {} ticks", self.x, self.y, error, backoff);
+        self.fault = Some(Fault { attempts, retry_at: tick + backoff });
+        None
+    }
+
+    fn panic_message(p: Box<dyn Any + Send>) -> String {
+        match p.downcast_ref::<&str>() {
+            Some(msg) => format!("panicked: {}", msg),
+            None => match p.downcast_ref::<String>() {
+                Some(msg) => format!("panicked: {}", msg),
+                None => "panicked".to_string()
+            }
+        }
+    }
+
+    /*
+     * Calls one of the lifecycle hooks. A hook failing is only logged, and doesn't count as a
+     * fault.
+     */
+    fn notify<F>(&mut self, hook: &str, f: F)
+        where F: FnOnce(&mut dyn PluginArea) -> Result<()> {
+        let error = match panic::catch_unwind(AssertUnwindSafe(|| f(self.area.as_mut()))) {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => format!("{:#}", e),
+            Err(p) => PadPlugin::panic_message(p)
+        };
+        eprintln!("Plugin at {},{} failed in {}: {}", self.x, self.y, hook, error);
+    }
+
+    fn input(&mut self, tick: u32, events: &Vec<PadEvent>) {
+        if self.fault.is_none() {
+            self.guard(tick, |p| p.process_input(tick, events));
+        }
+    }
+
+    fn output(&mut self, tick: u32) -> Vec<(PadLocation, PadColour)> {
+        let retry = self.fault.as_ref().is_none_or(|f| tick >= f.retry_at);
+        if retry {
+            if let Some(output) = self.guard(tick, |p| p.process_output(tick)) {
+                return output;
+            }
+        }
+        self.fault_pattern()
+    }
+
+    /*
+     * A red checkerboard over everything the plugin owns, carried on to its Letters in column 8.
+     */
+    fn fault_pattern(&self) -> Vec<(PadLocation, PadColour)> {
+        let colour = |x: u8, y: u8| PadColour::new(if (x + y).is_multiple_of(2) { 3 } else { 1 }, 0);
+        let mut result = Vec::new();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                result.push((PadLocation::on_pad(x + self.x, y + self.y), colour(x, y)));
+            }
+            if self.letters {
+                result.push((PadLocation::letter(y + self.y), colour(8 - self.x, y)));
+            }
+        }
+        result
+    }
+
+    /*
+     * Every cell the plugin owns, relative to it.
+     */
+    fn cells(&self) -> Vec<PadLocation> {
+        let mut result = Vec::new();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                result.push(PadLocation::on_pad(x, y));
+            }
+            if self.letters {
+                result.push(PadLocation::letter(y));
+            }
+        }
+        result
+    }
+
     fn translate(&self, loc: &PadLocation) -> Option<PadLocation> {
         match loc {
-            PadLocation::Letters(_) => None,
+            PadLocation::Letters(l) => if !self.letters || *l < self.y || *l >= self.y + self.height {
+                None
+            } else {
+                Some(PadLocation::Letters(*l - self.y))
+            },
             PadLocation::Numbers(_) => None,
             PadLocation::OnPad(x, y) => if *x < self.x || *x >= self.x + self.width
                 || *y < self.y || *y >= self.y + self.height {
@@ -132,36 +391,364 @@ impl PadPlugin<'_> {
 
 }
 
+/*
+ * A named set of plugins. Only one page is shown at a time, chosen with the Numbers row.
+ */
+struct Page<'a> {
+    name: String,
+    background: Background,
+    plugins: Vec<PadPlugin<'a>>
+}
+
+impl Page<'_> {
+    fn notify<F>(&mut self, hook: &str, f: F)
+        where F: Fn(&mut dyn PluginArea) -> Result<()> {
+        for plugin in &mut self.plugins {
+            plugin.notify(hook, &f);
+        }
+    }
+
+    /*
+     * As notify, but leaving out disabled plugins, which stay hidden whatever page is shown.
+     */
+    fn notify_enabled<F>(&mut self, hook: &str, f: F)
+        where F: Fn(&mut dyn PluginArea) -> Result<()> {
+        for plugin in self.plugins.iter_mut().filter(|p| p.enabled) {
+            plugin.notify(hook, &f);
+        }
+    }
+}
+
+/*
+ * What a plugin is and where, for clients of the control socket.
+ */
+pub struct PluginInfo {
+    pub page: String,
+    pub name: Option<String>,
+    pub x: u8,
+    pub y: u8,
+    pub width: u8,
+    pub height: u8,
+    pub enabled: bool,
+    pub failed: bool
+}
+
 /*
  * The entire blinkenPad with plugins etc.
  */
 pub struct BlinkenPad<'a> {
-    plugins: Vec<PadPlugin<'a>>,
-    pad: &'a mut LaunchPadMini<'a>,
+    pages: Vec<Page<'a>>,
+    page: usize,
+    pad: &'a mut (dyn PadArea + 'a),
     mirror: PadMirror,
-    ticks: u32
+    // Everything lit by the last step, whether or not it reached the pad.
+    frame: PadMirror,
+    // What the last step made of the input.
+    events: Vec<PadEvent>,
+    gestures: Gestures,
+    goodbye: Vec<(PadLocation, PadColour)>,
+    ticks: u32,
+    error_until: u32,
+    recorder: Option<Recorder>,
+    connected: bool,
+    // Whether the whole pad has to be repainted, as the mirror can't be trusted.
+    resync: bool
 }
 
+/*
+ * Numbers(7) quits, so the buttons before it select pages.
+ */
+const MAX_PAGES: usize = 7;
+
 impl <'a> BlinkenPad<'a> {
-    pub fn new(pad: &'a mut LaunchPadMini<'a>) -> BlinkenPad<'a> {
+    pub fn new(pad: &'a mut (dyn PadArea + 'a)) -> BlinkenPad<'a> {
+        let input = InputConfig::default();
         BlinkenPad::<'a> {
-            plugins: Vec::new(),
+            pages: Vec::new(),
+            page: 0,
             pad: pad,
             mirror: PadMirror::new(),
-            ticks: 0
+            frame: PadMirror::new(),
+            events: Vec::new(),
+            gestures: Gestures::new(input.long_press, input.double_tap),
+            goodbye: Vec::new(),
+            ticks: 0,
+            error_until: 0,
+            recorder: None,
+            connected: false,
+            resync: true
         }
     }
 
-    pub fn add_plugin(&mut self, x: u8, y: u8, width: u8, height: u8, area: Box<dyn PluginArea + 'a>) {
-        self.plugins.push(
+    /*
+     * Writes every step of process_all to the recorder from now on.
+     */
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub fn ticks(&self) -> u32 {
+        self.ticks
+    }
+
+    pub fn add_page(&mut self, name: &str, background: Background) {
+        self.pages.push(
+            Page {
+                name: name.to_string(), background, plugins: Vec::new()
+            }
+        );
+    }
+
+    /*
+     * Adds a plugin to the most recently added page, where its config places it. The plugin
+     * should already have had init called.
+     */
+    pub fn add_plugin(&mut self, plugin: &PluginConfig, area: Box<dyn PluginArea + 'a>) {
+        if self.pages.is_empty() {
+            self.add_page("main", Background::Run);
+        }
+        let page = self.pages.last_mut().unwrap();
+        page.plugins.push(
             PadPlugin {
-                x: x, y: y, width: width, height: height, area: area
+                name: plugin.name.clone(),
+                x: plugin.x,
+                y: plugin.y,
+                width: plugin.width,
+                height: plugin.height,
+                letters: plugin.letters,
+                area,
+                fault: None,
+                enabled: true
             }
         );
     }
 
+    /*
+     * Replaces all pages with the ones described in the config. Nothing is replaced unless
+     * every plugin could be built. The current page stays selected if it still exists, and
+     * plugins that were disabled by name stay disabled.
+     */
+    pub fn load(&mut self, config: &Config) -> Result<()> {
+        // Everything that can fail happens before the old layout is let go of.
+        let mut ports = MidiPorts::default();
+        for plugin in self.pages.iter().flat_map(|p| &p.plugins) {
+            plugin.area.hand_over(&mut ports);
+        }
+        let mut areas = Vec::new();
+        let ready = BlinkenPad::build_areas(config, &mut ports, &mut areas)
+            .and_then(|_| config.brightness.map_or(Ok(()), |percent| self.set_brightness(percent)));
+        if let Err(e) = ready {
+            for mut area in areas {
+                if let Err(e) = area.shutdown() {
+                    eprintln!("Plugin from the new layout failed in shutdown: {:#}", e);
+                }
+            }
+            return Err(e);
+        }
+        let current = self.pages.get(self.page).map(|p| p.name.clone());
+        let disabled: Vec<String> = self.pages.iter().flat_map(|p| &p.plugins)
+            .filter(|p| !p.enabled)
+            .filter_map(|p| p.name.clone())
+            .collect();
+        self.cleanup();
+        let mut areas = areas.into_iter();
+        for page in &config.pages {
+            self.add_page(&page.name, page.background);
+            for (plugin, area) in page.plugins.iter().zip(&mut areas) {
+                self.add_plugin(plugin, area);
+                if plugin.name.as_ref().is_some_and(|name| disabled.contains(name)) {
+                    self.pages.last_mut().unwrap().plugins.last_mut().unwrap().enabled = false;
+                }
+            }
+        }
+        self.page = current.and_then(|name| self.pages.iter().position(|p| p.name == name))
+            .unwrap_or(0);
+        if let Some(page) = self.pages.get_mut(self.page) {
+            page.notify_enabled("on_show", |a| a.on_show());
+        }
+        self.gestures.set_thresholds(config.input.long_press, config.input.double_tap);
+        self.goodbye = config.goodbye();
+        Ok(())
+    }
+
+    /*
+     * Builds and starts a plugin for every one in the config, in order, stopping at the first
+     * that fails. Those that did start are left in areas.
+     */
+    fn build_areas(config: &Config, ports: &mut MidiPorts,
+                   areas: &mut Vec<Box<dyn PluginArea + 'a>>) -> Result<()> {
+        for page in &config.pages {
+            for plugin in &page.plugins {
+                let mut area = plugin.build(ports).with_context(|| format!("On page {}", page.name))?;
+                area.init(plugin.width, plugin.height).with_context(|| format!("On page {}", page.name))?;
+                areas.push(area);
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Dims or brightens the whole pad, from 1 to 100 percent.
+     */
+    pub fn set_brightness(&mut self, percent: u8) -> Result<()> {
+        self.pad.set_brightness(percent.clamp(1, 100))
+    }
+
+    pub fn switch_page(&mut self, page: usize) {
+        if page >= self.pages.len() || page == self.page {
+            return;
+        }
+        self.pages[self.page].notify_enabled("on_hide", |a| a.on_hide());
+        self.page = page;
+        self.pages[self.page].notify_enabled("on_show", |a| a.on_show());
+    }
+
+    /*
+     * Shows the page with the given name.
+     */
+    pub fn show_page(&mut self, name: &str) -> Result<()> {
+        let page = self.pages.iter().position(|p| p.name == name)
+            .ok_or_else(|| Error::msg(format!("No page {}", name)))?;
+        self.switch_page(page);
+        Ok(())
+    }
+
+    pub fn page_names(&self) -> Vec<String> {
+        self.pages.iter().map(|p| p.name.clone()).collect()
+    }
+
+    /*
+     * The name of the page being shown.
+     */
+    pub fn page(&self) -> Option<&str> {
+        self.pages.get(self.page).map(|p| p.name.as_str())
+    }
+
+    pub fn plugins(&self) -> Vec<PluginInfo> {
+        self.pages.iter().flat_map(|page| page.plugins.iter().map(move |p| PluginInfo {
+            page: page.name.clone(),
+            name: p.name.clone(),
+            x: p.x,
+            y: p.y,
+            width: p.width,
+            height: p.height,
+            enabled: p.enabled,
+            failed: p.fault.is_some()
+        })).collect()
+    }
+
+    /*
+     * The page holding the plugin with the given name, and where it is on that page.
+     */
+    fn find_plugin(&self, name: &str) -> Result<(usize, usize)> {
+        for (i, page) in self.pages.iter().enumerate() {
+            if let Some(j) = page.plugins.iter().position(|p| p.name.as_deref() == Some(name)) {
+                return Ok((i, j));
+            }
+        }
+        Err(Error::msg(format!("No plugin {}", name)))
+    }
+
+    /*
+     * Turns a plugin on or off. A disabled plugin is hidden, and gets no input until it is
+     * enabled again.
+     */
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<()> {
+        let (page, index) = self.find_plugin(name)?;
+        let shown = page == self.page;
+        let plugin = &mut self.pages[page].plugins[index];
+        if plugin.enabled == enabled {
+            return Ok(());
+        }
+        plugin.enabled = enabled;
+        if shown && enabled {
+            plugin.notify("on_show", |a| a.on_show());
+        } else if shown {
+            plugin.notify("on_hide", |a| a.on_hide());
+        }
+        Ok(())
+    }
+
+    /*
+     * Lights cells of the named plugin, or turns them off with None. Locations are relative to
+     * the plugin.
+     */
+    pub fn set_cells(&mut self, name: &str, cells: &[(PadLocation, Option<PadColour>)]) -> Result<()> {
+        let (page, index) = self.find_plugin(name)?;
+        self.pages[page].plugins[index].set_cells(cells)
+    }
+
+    /*
+     * Turns off every cell of the named plugin.
+     */
+    pub fn clear_cells(&mut self, name: &str) -> Result<()> {
+        let (page, index) = self.find_plugin(name)?;
+        let plugin = &mut self.pages[page].plugins[index];
+        let cells: Vec<_> = plugin.cells().into_iter().map(|loc| (loc, None)).collect();
+        plugin.set_cells(&cells)
+    }
+
+    /*
+     * Every cell of the pad as of the last step, including while the pad is away.
+     */
+    pub fn lights(&self) -> Vec<(PadLocation, PadColour)> {
+        self.frame.cells()
+    }
+
+    /*
+     * What the last step made of the input from the pad.
+     */
+    pub fn events(&self) -> &[PadEvent] {
+        &self.events
+    }
+
+    /*
+     * Tells every plugin that the pad is there, when it first turns up or comes back.
+     */
+    fn device_connected(&mut self) {
+        for page in &mut self.pages {
+            page.notify("on_device_connected", |a| a.on_device_connected());
+        }
+    }
+
+    fn device_lost(&mut self) {
+        for page in &mut self.pages {
+            page.notify("on_device_lost", |a| a.on_device_lost());
+        }
+    }
+
+    fn page_buttons(&self) -> Vec<(PadLocation, PadColour)> {
+        (0..self.pages.len().min(MAX_PAGES)).map(|i| {
+            let colour = if i == self.page { PadColour::new(0, 3) } else { PadColour::new(0, 1) };
+            (PadLocation::number(i as u8), colour)
+        }).collect()
+    }
+
+    /*
+     * Shows a red cross over the whole pad for a couple of seconds, e.g. when a config reload
+     * fails.
+     */
+    pub fn show_error(&mut self) {
+        self.error_until = self.ticks + 20;
+    }
+
+    fn error_pattern() -> Vec<(PadLocation, PadColour)> {
+        let mut result = Vec::new();
+        for i in 0..8 {
+            result.push((PadLocation::on_pad(i, i), PadColour::new(3, 0)));
+            result.push((PadLocation::on_pad(7 - i, i), PadColour::new(3, 0)));
+        }
+        result
+    }
+
+    /*
+     * Shuts down and drops every plugin.
+     */
     pub fn cleanup(&mut self) {
-        self.plugins.clear();
+        for page in &mut self.pages {
+            page.notify("shutdown", |a| a.shutdown());
+        }
+        self.pages.clear();
     }
 
     pub fn clear_pad(&mut self) -> Result<()> {
@@ -178,25 +765,184 @@ impl <'a> BlinkenPad<'a> {
         self.pad.process_in(commands)
     }
 
-    pub fn process_all(&mut self) -> Result<bool> {
-        self.ticks += 1;
-        let out = self.pad.process_out()?;
-        for plugin in &mut self.plugins {
-            plugin.process_input(self.ticks, &out).context("On plugin input")?;
+    /*
+     * Everything the main loop should wait on: the pad itself and any plugins that are running.
+     */
+    pub fn poll_descriptors(&self) -> Result<Vec<pollfd>> {
+        let mut result = self.pad.poll_descriptors()?;
+        for (i, page) in self.pages.iter().enumerate() {
+            if i != self.page && page.background == Background::Suspend {
+                continue;
+            }
+            for plugin in page.plugins.iter().filter(|p| p.enabled) {
+                result.append(&mut plugin.poll_descriptors()?);
+            }
         }
+        Ok(result)
+    }
 
-        let mut lights = Vec::new();
-        for plugin in &mut self.plugins {
-            lights.append(&mut plugin.process_output(self.ticks).context("On plugin output")?);
+    /*
+     * Lights the configured goodbye frame, for after the pad has been cleared on exit.
+     */
+    pub fn show_goodbye(&mut self) -> Result<()> {
+        let goodbye = self.goodbye.clone();
+        self.mirror.update(&goodbye);
+        self.pad.process_in(goodbye)
+    }
+
+    /*
+     * Notices the pad coming and going. While it is away nothing reaches the plugins, and when
+     * it comes back it is repainted from scratch.
+     */
+    fn check_connection(&mut self) {
+        let connected = self.pad.connected();
+        if connected == self.connected {
+            return;
+        }
+        self.connected = connected;
+        if connected {
+            eprintln!("Pad connected");
+            self.resync = true;
+            self.device_connected();
+        } else {
+            eprintln!("Pad disconnected");
+            self.gestures.reset();
+            self.device_lost();
+        }
+    }
+
+    /*
+     * Handles any input and repaints. Ticks drive animations, long presses and the like, so the
+     * tick count only moves on when `tick` is set. Errors talking to the pad are logged rather
+     * than returned: a pad that can't be read is taken to have been unplugged, and one that can't
+     * be written to is repainted in full next time.
+     */
+    pub fn process_all(&mut self, tick: bool) -> Result<bool> {
+        if tick {
+            self.ticks += 1;
         }
-        if self.ticks % 50 == 0 {
+        let input = match self.pad.process_out() {
+            Ok(input) => input,
+            Err(e) => {
+                // As if it had been unplugged, so that it comes back repainted.
+                eprintln!("Reading from the pad failed: {:#}", e);
+                if self.connected {
+                    self.connected = false;
+                    self.gestures.reset();
+                    self.device_lost();
+                }
+                Vec::new()
+            }
+        };
+        self.check_connection();
+        let out = if self.connected { self.gestures.process(self.ticks, &input) } else { Vec::new() };
+        for ev in &out {
+            if let PadEvent::Press(PadLocation::Numbers(n)) = ev {
+                self.switch_page(*n as usize);
+            }
+        }
+        if let Some(page) = self.pages.get_mut(self.page) {
+            for plugin in page.plugins.iter_mut().filter(|p| p.enabled) {
+                plugin.input(self.ticks, &out);
+            }
+        }
+
+        let mut lights = self.page_buttons();
+        for (i, page) in self.pages.iter_mut().enumerate() {
+            if i != self.page && page.background == Background::Suspend {
+                continue;
+            }
+            for plugin in page.plugins.iter_mut().filter(|p| p.enabled) {
+                let mut output = plugin.output(self.ticks);
+                if i == self.page {
+                    lights.append(&mut output);
+                }
+            }
+        }
+        if self.ticks < self.error_until {
+            lights.append(&mut BlinkenPad::error_pattern());
+        }
+        if tick && self.ticks.is_multiple_of(50) {
             self.mirror.clear();
         }
-        let min_lights = self.mirror.minimise(lights);
+        // Anything no plugin lit is turned off, so cells left behind by a reload go dark.
+        let mut frame = PadMirror::new();
+        frame.update(&lights);
+        // Nothing is sent while the pad is away; it is repainted in full when it is back.
+        let min_lights = if !self.connected {
+            Vec::new()
+        } else if self.resync {
+            self.resync = false;
+            frame.cells()
+        } else {
+            self.mirror.diff(&frame)
+        };
 
         self.mirror.update(&min_lights);
-        self.pad.process_in(min_lights)?;
-        Ok(out.contains(&PadLocation::number(7)))
+        self.frame = frame;
+        self.events = out.clone();
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(self.ticks, tick, &input, &min_lights)?;
+        }
+        if !self.connected {
+            return Ok(false);
+        }
+        if let Err(e) = self.pad.process_in(min_lights) {
+            eprintln!("Writing to the pad failed: {:#}", e);
+            self.resync = true;
+        }
+        Ok(out.contains(&PadEvent::Press(PadLocation::number(7))))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Failing;
+
+    impl PluginArea for Failing {
+        fn process_input(&mut self, _tick: u32, _events: &Vec<PadEvent>) -> Result<()> {
+            Ok(())
+        }
+
+        fn process_output(&mut self, _tick: u32) -> Result<Vec<(PadLocation, PadColour)>> {
+            Err(Error::msg("broken"))
+        }
+    }
+
+    fn failing(x: u8, y: u8, letters: bool) -> PadPlugin<'static> {
+        PadPlugin {
+            name: None,
+            x,
+            y,
+            width: 2,
+            height: 2,
+            letters,
+            area: Box::new(Failing),
+            fault: None,
+            enabled: true
+        }
+    }
+
+    #[test]
+    fn fault_pattern_covers_the_area() {
+        let (red, dim) = (PadColour::new(3, 0), PadColour::new(1, 0));
+        assert_eq!(failing(3, 4, false).output(0), vec![
+            (PadLocation::on_pad(3, 4), red),
+            (PadLocation::on_pad(4, 4), dim),
+            (PadLocation::on_pad(3, 5), dim),
+            (PadLocation::on_pad(4, 5), red)
+        ]);
+    }
+
+    #[test]
+    fn fault_pattern_covers_owned_letters() {
+        let (red, dim) = (PadColour::new(3, 0), PadColour::new(1, 0));
+        let output = failing(6, 1, true).output(0);
+        assert_eq!(output.len(), 6);
+        assert!(output.contains(&(PadLocation::on_pad(7, 1), dim)));
+        assert!(output.contains(&(PadLocation::letter(1), red)));
+        assert!(output.contains(&(PadLocation::letter(2), dim)));
+    }
+}
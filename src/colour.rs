@@ -0,0 +1,243 @@
+use std::fmt;
+
+/*
+ * A colour for a button, held as 8-bit RGB so that plugins can pick it once for every model.
+ * Each model converts it as well as it can: the Mini Mk1 only has red and green LEDs with four
+ * levels each, the RGB models have a palette of 128 colours or take RGB directly.
+ *
+ * A flashing colour is flashed by the pad itself, so it needs no repainting to keep going.
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PadColour {
+    red: u8,
+    green: u8,
+    blue: u8,
+    flashing: bool
+}
+
+// Centre hues of the colour groups in the Novation palette, which start at index 4 and come in
+// fours: pale, bright, medium, dim.
+const PALETTE_HUES: [u32; 14] = [0, 30, 60, 90, 120, 140, 160, 180, 200, 220, 240, 270, 300, 330];
+
+impl PadColour {
+    /*
+     * A colour in Mini Mk1 terms: red and green levels from 0 to 3.
+     */
+    pub fn new(red: u8, green: u8) -> PadColour {
+        assert!(red < 4);
+        assert!(green < 4);
+        PadColour {
+            red: red * 85,
+            green: green * 85,
+            blue: 0,
+            flashing: false
+        }
+    }
+
+    pub const fn rgb(red: u8, green: u8, blue: u8) -> PadColour {
+        PadColour {
+            red,
+            green,
+            blue,
+            flashing: false
+        }
+    }
+
+    /*
+     * The same colour, flashing on and off.
+     */
+    pub fn flashing(self) -> PadColour {
+        PadColour {
+            flashing: true,
+            ..self
+        }
+    }
+
+    pub fn is_flashing(&self) -> bool {
+        self.flashing
+    }
+
+    /*
+     * Reads "#rrggbb" or one of a few names.
+     */
+    pub fn parse(text: &str) -> Option<PadColour> {
+        if let Some(hex) = text.strip_prefix('#') {
+            if hex.len() != 6 || !hex.is_ascii() {
+                return None;
+            }
+            let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+            return Some(PadColour::rgb(channel(0)?, channel(2)?, channel(4)?));
+        }
+        Some(match text {
+            "off" | "black" => PadColour::rgb(0, 0, 0),
+            "red" => PadColour::rgb(255, 0, 0),
+            "orange" => PadColour::rgb(255, 128, 0),
+            "amber" => PadColour::rgb(255, 192, 0),
+            "yellow" => PadColour::rgb(255, 255, 0),
+            "lime" => PadColour::rgb(128, 255, 0),
+            "green" => PadColour::rgb(0, 255, 0),
+            "cyan" => PadColour::rgb(0, 255, 255),
+            "blue" => PadColour::rgb(0, 0, 255),
+            "purple" => PadColour::rgb(128, 0, 255),
+            "magenta" => PadColour::rgb(255, 0, 255),
+            "pink" => PadColour::rgb(255, 0, 128),
+            "white" => PadColour::rgb(255, 255, 255),
+            _ => return None
+        })
+    }
+
+    pub fn components(&self) -> (u8, u8, u8) {
+        (self.red, self.green, self.blue)
+    }
+
+    /*
+     * Red and green levels for the Mini Mk1. Blue has no LED there, so it shows as a little of
+     * both. Anything the palette wouldn't show as black stays at least dimly lit.
+     */
+    pub fn mk1_levels(&self) -> (u8, u8) {
+        let red = self.red.max(self.blue / 2);
+        let green = self.green.max(self.blue / 2);
+        let level = |c: u8| ((c as u32 + 42) / 85) as u8;
+        let (r, g) = (level(red), level(green));
+        if r == 0 && g == 0 && red.max(green) >= 16 {
+            return ((red >= 16) as u8, (green >= 16) as u8);
+        }
+        (r, g)
+    }
+
+    /*
+     * The note velocity that shows this colour on the Mini Mk1.
+     */
+    pub fn mk1_velocity(&self) -> u8 {
+        let (red, green) = self.mk1_levels();
+        red | (green << 4)
+    }
+
+    /*
+     * The closest entry in the palette shared by the Mk2, Mini Mk3 and X. This is approximate:
+     * it picks a hue group and a brightness within it.
+     */
+    pub fn palette_index(&self) -> u8 {
+        let (r, g, b) = (self.red as u32, self.green as u32, self.blue as u32);
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        if max < 16 {
+            return 0;
+        }
+        // Greys: dark grey, grey and white.
+        if (max - min) * 4 < max {
+            return if max < 96 { 1 } else if max < 192 { 2 } else { 3 };
+        }
+        let delta = (max - min) as i32;
+        let hue = if max == r {
+            60 * (g as i32 - b as i32) / delta
+        } else if max == g {
+            120 + 60 * (b as i32 - r as i32) / delta
+        } else {
+            240 + 60 * (r as i32 - g as i32) / delta
+        }.rem_euclid(360) as u32;
+        let group = (0..PALETTE_HUES.len()).min_by_key(|i| {
+            let d = PALETTE_HUES[*i].abs_diff(hue);
+            d.min(360 - d)
+        }).unwrap_or(0) as u8;
+        let shade = if (max - min) * 5 < max * 3 {
+            0
+        } else if max >= 192 {
+            1
+        } else if max >= 96 {
+            2
+        } else {
+            3
+        };
+        4 + group * 4 + shade
+    }
+}
+
+impl fmt::Display for PadColour {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)?;
+        if self.flashing {
+            write!(f, " flash")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mk1_levels_of_the_corners() {
+        assert_eq!(PadColour::rgb(0, 0, 0).mk1_levels(), (0, 0));
+        assert_eq!(PadColour::rgb(255, 0, 0).mk1_levels(), (3, 0));
+        assert_eq!(PadColour::rgb(0, 255, 0).mk1_levels(), (0, 3));
+        assert_eq!(PadColour::rgb(255, 255, 0).mk1_levels(), (3, 3));
+        assert_eq!(PadColour::rgb(255, 255, 255).mk1_levels(), (3, 3));
+        // Blue shows as a little of both.
+        assert_eq!(PadColour::rgb(0, 0, 255).mk1_levels(), (1, 1));
+        assert_eq!(PadColour::rgb(255, 0, 255).mk1_levels(), (3, 1));
+        assert_eq!(PadColour::rgb(0, 255, 255).mk1_levels(), (1, 3));
+    }
+
+    #[test]
+    fn mk1_levels_round_trip() {
+        for red in 0..4 {
+            for green in 0..4 {
+                let colour = PadColour::new(red, green);
+                assert_eq!(colour.mk1_levels(), (red, green));
+                assert_eq!(colour.mk1_velocity(), red | green << 4);
+            }
+        }
+    }
+
+    #[test]
+    fn mk1_levels_keep_dim_colours_lit() {
+        // The dim off colour, which would otherwise round down to nothing.
+        assert_eq!(PadColour::rgb(32, 32, 32).mk1_levels(), (1, 1));
+        assert_eq!(PadColour::rgb(16, 0, 0).mk1_levels(), (1, 0));
+        assert_eq!(PadColour::rgb(15, 15, 15).mk1_levels(), (0, 0));
+        assert_eq!(PadColour::rgb(42, 0, 0).mk1_levels(), (1, 0));
+        assert_eq!(PadColour::rgb(128, 0, 0).mk1_levels(), (2, 0));
+        assert_eq!(PadColour::rgb(212, 0, 0).mk1_levels(), (2, 0));
+        assert_eq!(PadColour::rgb(213, 0, 0).mk1_levels(), (3, 0));
+    }
+
+    #[test]
+    fn palette_index_of_the_corners() {
+        assert_eq!(PadColour::rgb(0, 0, 0).palette_index(), 0);
+        assert_eq!(PadColour::rgb(255, 255, 255).palette_index(), 3);
+        assert_eq!(PadColour::rgb(255, 0, 0).palette_index(), 5);
+        assert_eq!(PadColour::rgb(255, 255, 0).palette_index(), 13);
+        assert_eq!(PadColour::rgb(0, 255, 0).palette_index(), 21);
+        assert_eq!(PadColour::rgb(0, 255, 255).palette_index(), 33);
+        assert_eq!(PadColour::rgb(0, 0, 255).palette_index(), 45);
+        assert_eq!(PadColour::rgb(255, 0, 255).palette_index(), 53);
+    }
+
+    #[test]
+    fn palette_index_of_greys_and_shades() {
+        assert_eq!(PadColour::rgb(15, 15, 15).palette_index(), 0);
+        assert_eq!(PadColour::rgb(32, 32, 32).palette_index(), 1);
+        assert_eq!(PadColour::rgb(128, 128, 128).palette_index(), 2);
+        // Pale, bright, medium and dim red.
+        assert_eq!(PadColour::rgb(255, 128, 128).palette_index(), 4);
+        assert_eq!(PadColour::rgb(255, 0, 0).palette_index(), 5);
+        assert_eq!(PadColour::rgb(128, 0, 0).palette_index(), 6);
+        assert_eq!(PadColour::rgb(64, 0, 0).palette_index(), 7);
+        assert_eq!(PadColour::rgb(16, 0, 0).palette_index(), 7);
+    }
+
+    #[test]
+    fn flashing_keeps_the_colour() {
+        let colour = PadColour::rgb(255, 128, 0);
+        let flashing = colour.flashing();
+        assert!(flashing.is_flashing());
+        assert!(!colour.is_flashing());
+        assert_ne!(flashing, colour);
+        assert_eq!(flashing.components(), colour.components());
+        assert_eq!(flashing.mk1_levels(), colour.mk1_levels());
+        assert_eq!(flashing.palette_index(), colour.palette_index());
+        assert_eq!(flashing.to_string(), "#ff8000 flash");
+    }
+}
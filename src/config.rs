@@ -0,0 +1,971 @@
+extern crate serde;
+extern crate toml;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::time::SystemTime;
+use serde::Deserialize;
+use anyhow::{ Result, Error, Context };
+use regex::Regex;
+use crate::alsa_midi::{ DeviceMatch, DEFAULT_CLIENT };
+use crate::launchpad::{ PadColour, PadLocation };
+use crate::blinken::{ PluginArea, PadLoopback };
+use crate::mixer_plugin::MixerPlugin;
+use crate::mpris_plugin::MprisPlugin;
+use crate::xdo_plugin::{ XdoPlugin, XdoKey, XdoAction };
+use crate::midi_plugin::{ MidiPlugin, MidiCell, MidiAction, MidiPorts };
+use crate::remote_plugin::RemotePlugin;
+use crate::shell_plugin::{ ShellPlugin, ShellCell, ShellStatus };
+use crate::dbus_plugin::{ self, DbusPlugin, DbusCell, DbusBus, DbusBinding, DbusSource };
+
+/*
+ * The layout of the pad, as read from a TOML file. Plugins given at the top level end up on a
+ * page called "main", ahead of any other pages.
+ */
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    plugins: Vec<PluginConfig>,
+    #[serde(default)]
+    pub pages: Vec<PageConfig>,
+    #[serde(default)]
+    pub input: InputConfig,
+    #[serde(default)]
+    goodbye: Vec<String>,
+    // In percent; the pad's own default when not given.
+    #[serde(default)]
+    pub brightness: Option<u8>,
+    #[serde(default)]
+    pub device: DeviceConfig
+}
+
+/*
+ * Which MIDI port is the pad, for when the first Launchpad to answer isn't the right one. client
+ * and port are regular expressions found anywhere in the names; address is "client:port". With
+ * none of them, only clients called something with "Launchpad" are tried, unless any is set, which
+ * tries every port. Only read on startup.
+ *
+ * With the rawmidi backend the pad is the given rawmidi device, e.g. "hw:1,0", or the first one
+ * on a sound card whose name matches client.
+ */
+#[derive(Deserialize, Clone, Default)]
+pub struct DeviceConfig {
+    #[serde(default)]
+    pub backend: MidiBackend,
+    pub client: Option<String>,
+    pub port: Option<String>,
+    pub address: Option<String>,
+    #[serde(default)]
+    pub any: bool,
+    pub rawmidi: Option<String>
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MidiBackend {
+    #[default]
+    Sequencer,
+    Rawmidi
+}
+
+/*
+ * Gesture thresholds, in ticks of 100ms.
+ */
+#[derive(Deserialize)]
+pub struct InputConfig {
+    #[serde(default = "default_long_press")]
+    pub long_press: u32,
+    #[serde(default = "default_double_tap")]
+    pub double_tap: u32
+}
+
+impl Default for InputConfig {
+    fn default() -> InputConfig {
+        InputConfig {
+            long_press: default_long_press(),
+            double_tap: default_double_tap()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PageConfig {
+    pub name: String,
+    #[serde(default)]
+    pub background: Background,
+    #[serde(default)]
+    pub plugins: Vec<PluginConfig>
+}
+
+/*
+ * What the plugins on a page do while another page is shown.
+ */
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Background {
+    #[default]
+    Run,
+    Suspend
+}
+
+/*
+ * A single plugin instance, and the rectangle of the pad it owns. With letters set it also owns
+ * the Letters buttons on the same rows, for the kinds that use them. A name lets it be found
+ * through the control socket.
+ */
+#[derive(Deserialize)]
+pub struct PluginConfig {
+    pub name: Option<String>,
+    pub x: u8,
+    pub y: u8,
+    pub width: u8,
+    pub height: u8,
+    #[serde(default)]
+    pub letters: bool,
+    #[serde(flatten)]
+    pub kind: PluginKind
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum PluginKind {
+    Xdo {
+        #[serde(default)]
+        keys: Vec<KeyConfig>
+    },
+    Mpris,
+    Mixer {
+        #[serde(default = "default_mixer_device")]
+        device: String,
+        #[serde(default = "default_mixer_playback")]
+        playback: String,
+        #[serde(default = "default_mixer_capture")]
+        capture: String
+    },
+    Midi {
+        #[serde(default = "default_midi_port")]
+        port: String,
+        #[serde(default)]
+        channel: u8,
+        #[serde(default)]
+        cells: Vec<MidiCellConfig>
+    },
+    Dbus {
+        #[serde(default)]
+        cells: Vec<DbusCellConfig>
+    },
+    Shell {
+        #[serde(default)]
+        cells: Vec<ShellCellConfig>
+    },
+    // Kept for clients of the control socket to light.
+    Remote,
+    Loopback
+}
+
+/*
+ * A cell of an xdo plugin, which either types key or does something with the mouse. Held keys
+ * and clicks stay down while the button is. Moves, drags and scrolls go by [x, y], or to [x, y]
+ * on screen; moves and scrolls by an amount keep going while held if they repeat.
+ */
+#[derive(Deserialize)]
+pub struct KeyConfig {
+    pub x: u8,
+    pub y: u8,
+    pub key: Option<String>,
+    pub mouse: Option<MouseConfig>,
+    #[serde(default = "default_mouse_button")]
+    pub button: u8,
+    pub to: Option<(i32, i32)>,
+    pub by: Option<(i32, i32)>,
+    #[serde(default)]
+    pub screen: i32,
+    #[serde(default)]
+    pub repeat: bool,
+    pub colour: ColourConfig,
+    #[serde(default)]
+    pub hold: bool
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MouseConfig {
+    Click,
+    DoubleClick,
+    Move,
+    Drag,
+    Scroll
+}
+
+/*
+ * A cell of a MIDI plugin. The action says what it sends: a note, a controller that toggles or
+ * is on while held, or a program change. It is lit with colour while on, and off otherwise.
+ */
+#[derive(Deserialize)]
+pub struct MidiCellConfig {
+    pub x: u8,
+    pub y: u8,
+    pub colour: ColourConfig,
+    pub off: Option<ColourConfig>,
+    pub channel: Option<u8>,
+    #[serde(flatten)]
+    pub action: MidiActionConfig
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum MidiActionConfig {
+    Note {
+        note: u8,
+        #[serde(default = "default_midi_velocity")]
+        velocity: u8
+    },
+    Toggle {
+        cc: u8
+    },
+    Momentary {
+        cc: u8
+    },
+    Program {
+        program: u8
+    }
+}
+
+/*
+ * A cell of a D-Bus plugin, which calls method on the object at path when pressed. Arguments
+ * are written as for dbus-send, e.g. "string:hello" or "array:int32:1,2,3". The cell is lit
+ * with colour, or as bind says.
+ */
+#[derive(Deserialize)]
+pub struct DbusCellConfig {
+    pub x: u8,
+    pub y: u8,
+    #[serde(default)]
+    pub bus: DbusBusConfig,
+    pub destination: String,
+    pub path: String,
+    pub interface: String,
+    pub method: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub colour: ColourConfig,
+    pub bind: Option<DbusBindConfig>
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DbusBusConfig {
+    #[default]
+    Session,
+    System
+}
+
+/*
+ * Lights a cell from a property or from the first argument of a signal, on the cell's
+ * destination. The interface and path default to the cell's. values maps a value, as text, to
+ * a colour; other values light the cell in its colour if they are true, and in off if not.
+ */
+#[derive(Deserialize)]
+pub struct DbusBindConfig {
+    pub property: Option<String>,
+    pub signal: Option<String>,
+    pub interface: Option<String>,
+    pub path: Option<String>,
+    pub off: Option<ColourConfig>,
+    #[serde(default)]
+    pub values: HashMap<String, ColourConfig>
+}
+
+/*
+ * A cell of a shell plugin, which runs command through sh when pressed. It is lit with colour,
+ * or as status says.
+ */
+#[derive(Deserialize)]
+pub struct ShellCellConfig {
+    pub x: u8,
+    pub y: u8,
+    pub command: String,
+    pub colour: ColourConfig,
+    pub status: Option<ShellStatusConfig>
+}
+
+/*
+ * A command run every interval ticks, and after each press, to find the cell's colour. values
+ * maps the first line it prints to a colour; otherwise the cell is lit in its colour if the
+ * command succeeds, and in off if it fails.
+ */
+#[derive(Deserialize)]
+pub struct ShellStatusConfig {
+    pub command: String,
+    #[serde(default = "default_shell_interval")]
+    pub interval: u32,
+    pub off: Option<ColourConfig>,
+    #[serde(default)]
+    pub values: HashMap<String, ColourConfig>
+}
+
+/*
+ * A colour as [red, green] levels from 0 to 3 as on the Mini Mk1, as [red, green, blue] from 0
+ * to 255, or as "#rrggbb" or a name such as "amber".
+ */
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ColourConfig {
+    Levels(u8, u8),
+    Rgb(u8, u8, u8),
+    Named(String)
+}
+
+/*
+ * Notices when the config file has been written to, by polling its modification time.
+ */
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>
+}
+
+fn default_long_press() -> u32 { 10 }
+fn default_double_tap() -> u32 { 4 }
+fn default_mixer_device() -> String { "pulse".to_string() }
+fn default_mixer_playback() -> String { "Master".to_string() }
+fn default_mixer_capture() -> String { "Capture".to_string() }
+fn default_midi_port() -> String { "MIDI".to_string() }
+fn default_midi_velocity() -> u8 { 100 }
+fn default_shell_interval() -> u32 { 50 }
+fn default_mouse_button() -> u8 { 1 }
+
+// Unless a cell picks its own off colour, it stays dimly lit when off so that it can be found.
+const DIM: PadColour = PadColour::rgb(32, 32, 32);
+
+fn off_colour(off: &Option<ColourConfig>) -> PadColour {
+    off.as_ref().map(|c| c.colour()).unwrap_or(DIM)
+}
+
+impl ColourConfig {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            ColourConfig::Levels(red, green) if *red > 3 || *green > 3 =>
+                Err(Error::msg(format!("Colour [{}, {}] out of range 0-3", red, green))),
+            ColourConfig::Named(name) if PadColour::parse(name).is_none() =>
+                Err(Error::msg(format!("Unknown colour {}", name))),
+            _ => Ok(())
+        }
+    }
+
+    pub fn colour(&self) -> PadColour {
+        match self {
+            ColourConfig::Levels(red, green) => PadColour::new(*red, *green),
+            ColourConfig::Rgb(red, green, blue) => PadColour::rgb(*red, *green, *blue),
+            ColourConfig::Named(name) => PadColour::parse(name).unwrap_or(PadColour::new(0, 0))
+        }
+    }
+}
+
+impl KeyConfig {
+    fn action(&self) -> Result<XdoAction> {
+        let fail = |what: &str| Err(Error::msg(format!("Cell {} {}: {}", self.x, self.y, what)));
+        let button = self.button as i32;
+        if self.button == 0 {
+            return fail("mouse buttons count from 1");
+        }
+        let action = match (&self.key, self.mouse, self.to, self.by) {
+            (Some(_), Some(_), _, _) | (None, None, _, _) => return fail("needs either a key or a mouse action"),
+            (Some(_), None, Some(_), _) | (Some(_), None, _, Some(_)) => return fail("keys don't take to or by"),
+            (Some(key), None, None, None) => XdoAction::Keys { key: key.clone(), hold: self.hold },
+            (None, Some(_), Some(_), Some(_)) => return fail("takes either to or by, not both"),
+            (None, Some(MouseConfig::Click), None, None) => XdoAction::Click { button, hold: self.hold },
+            (None, Some(MouseConfig::DoubleClick), None, None) => XdoAction::DoubleClick { button },
+            (None, Some(MouseConfig::Click | MouseConfig::DoubleClick), _, _) => return fail("clicks don't take to or by"),
+            (None, Some(MouseConfig::Move), Some((x, y)), None) => XdoAction::MoveTo { x, y, screen: self.screen },
+            (None, Some(MouseConfig::Move), None, Some((x, y))) => XdoAction::MoveBy { x, y, repeat: self.repeat },
+            (None, Some(MouseConfig::Drag), Some((x, y)), None) =>
+                XdoAction::DragTo { button, x, y, screen: self.screen },
+            (None, Some(MouseConfig::Drag), None, Some((x, y))) => XdoAction::DragBy { button, x, y },
+            (None, Some(MouseConfig::Scroll), None, Some((x, y))) => XdoAction::Scroll { x, y, repeat: self.repeat },
+            (None, Some(MouseConfig::Scroll), _, _) => return fail("scroll needs by"),
+            (None, Some(_), None, None) => return fail("needs to or by")
+        };
+        match &action {
+            XdoAction::Keys { .. } | XdoAction::Click { .. } => {},
+            _ if self.hold => return fail("only keys and clicks can be held"),
+            _ => {}
+        }
+        match &action {
+            XdoAction::MoveBy { .. } | XdoAction::Scroll { .. } => {},
+            _ if self.repeat => return fail("only moves and scrolls by an amount can repeat"),
+            _ => {}
+        }
+        Ok(action)
+    }
+}
+
+impl MidiCellConfig {
+    fn validate(&self, channel: u8) -> Result<()> {
+        if self.channel.unwrap_or(channel) > 15 {
+            return Err(Error::msg(format!("Cell {} {}: channel out of range 0-15", self.x, self.y)));
+        }
+        let value = match self.action {
+            MidiActionConfig::Note { note, velocity } => {
+                if velocity == 0 || velocity > 127 {
+                    return Err(Error::msg(format!("Cell {} {}: velocity out of range 1-127", self.x, self.y)));
+                }
+                note
+            },
+            MidiActionConfig::Toggle { cc } | MidiActionConfig::Momentary { cc } => cc,
+            MidiActionConfig::Program { program } => program
+        };
+        if value > 127 {
+            return Err(Error::msg(format!("Cell {} {}: {} out of range 0-127", self.x, self.y, value)));
+        }
+        self.colour.validate()?;
+        if let Some(off) = &self.off {
+            off.validate()?;
+        }
+        Ok(())
+    }
+
+    fn cell(&self, channel: u8) -> MidiCell {
+        MidiCell {
+            x: self.x,
+            y: self.y,
+            channel: self.channel.unwrap_or(channel),
+            action: match self.action {
+                MidiActionConfig::Note { note, velocity } => MidiAction::Note { note, velocity },
+                MidiActionConfig::Toggle { cc } => MidiAction::Toggle { cc },
+                MidiActionConfig::Momentary { cc } => MidiAction::Momentary { cc },
+                MidiActionConfig::Program { program } => MidiAction::Program { program }
+            },
+            colour: self.colour.colour(),
+            off: off_colour(&self.off)
+        }
+    }
+}
+
+impl DbusCellConfig {
+    fn validate(&self) -> Result<()> {
+        let context = || format!("Cell {} {}", self.x, self.y);
+        dbus_plugin::validate_names(&self.destination, &self.path, &self.interface, &self.method)
+            .with_context(context)?;
+        for arg in &self.args {
+            dbus_plugin::parse_arg(arg).with_context(context)?;
+        }
+        self.colour.validate()?;
+        if let Some(bind) = &self.bind {
+            if bind.property.is_some() == bind.signal.is_some() {
+                return Err(Error::msg(format!("{}: bind needs either a property or a signal", context())));
+            }
+            let member = bind.property.as_ref().or(bind.signal.as_ref()).map(|m| m.as_str()).unwrap_or("");
+            dbus_plugin::validate_names(&self.destination, self.bind_path(bind), self.bind_interface(bind), member)
+                .with_context(context)?;
+            if let Some(off) = &bind.off {
+                off.validate()?;
+            }
+            for colour in bind.values.values() {
+                colour.validate()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn bind_path<'a>(&'a self, bind: &'a DbusBindConfig) -> &'a str {
+        bind.path.as_deref().unwrap_or(&self.path)
+    }
+
+    fn bind_interface<'a>(&'a self, bind: &'a DbusBindConfig) -> &'a str {
+        bind.interface.as_deref().unwrap_or(&self.interface)
+    }
+
+    fn cell(&self) -> Result<DbusCell> {
+        Ok(DbusCell {
+            x: self.x,
+            y: self.y,
+            bus: match self.bus {
+                DbusBusConfig::Session => DbusBus::Session,
+                DbusBusConfig::System => DbusBus::System
+            },
+            destination: self.destination.clone(),
+            path: self.path.clone(),
+            interface: self.interface.clone(),
+            method: self.method.clone(),
+            args: self.args.iter().map(|a| dbus_plugin::parse_arg(a)).collect::<Result<_>>()?,
+            colour: self.colour.colour(),
+            binding: self.bind.as_ref().map(|bind| DbusBinding {
+                source: match (&bind.property, &bind.signal) {
+                    (Some(property), _) => DbusSource::Property(property.clone()),
+                    (None, signal) => DbusSource::Signal(signal.clone().unwrap_or_default())
+                },
+                path: self.bind_path(bind).to_string(),
+                interface: self.bind_interface(bind).to_string(),
+                off: off_colour(&bind.off),
+                values: bind.values.iter().map(|(k, v)| (k.clone(), v.colour())).collect()
+            })
+        })
+    }
+}
+
+impl ShellCellConfig {
+    fn validate(&self) -> Result<()> {
+        self.colour.validate()?;
+        if let Some(status) = &self.status {
+            if status.interval == 0 {
+                return Err(Error::msg(format!("Cell {} {}: status interval must be at least 1", self.x, self.y)));
+            }
+            if let Some(off) = &status.off {
+                off.validate()?;
+            }
+            for colour in status.values.values() {
+                colour.validate()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn cell(&self) -> ShellCell {
+        ShellCell {
+            x: self.x,
+            y: self.y,
+            command: self.command.clone(),
+            colour: self.colour.colour(),
+            status: self.status.as_ref().map(|status| ShellStatus {
+                command: status.command.clone(),
+                interval: status.interval,
+                off: off_colour(&status.off),
+                values: status.values.iter().map(|(k, v)| (k.clone(), v.colour())).collect()
+            })
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn matcher(&self) -> Result<DeviceMatch> {
+        let regex = |r: &Option<String>| r.as_ref().map(|r| Regex::new(r)).transpose();
+        Ok(DeviceMatch {
+            client: regex(&self.client).context("Device client")?,
+            port: regex(&self.port).context("Device port")?,
+            address: self.address.as_ref().map(|a| DeviceMatch::parse_address(a)).transpose()?,
+            any: self.any
+        })
+    }
+
+    /*
+     * The pattern for sound card names when looking for a rawmidi device.
+     */
+    pub fn card(&self) -> Result<Regex> {
+        Regex::new(self.client.as_deref().unwrap_or(DEFAULT_CLIENT)).context("Device client")
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.matcher()?;
+        if self.any && (self.client.is_some() || self.port.is_some() || self.address.is_some()) {
+            return Err(Error::msg("Device any can't be used with client, port or address"));
+        }
+        match self.backend {
+            MidiBackend::Sequencer if self.rawmidi.is_some() =>
+                Err(Error::msg("Device rawmidi needs the rawmidi backend")),
+            MidiBackend::Rawmidi if self.port.is_some() || self.address.is_some() || self.any =>
+                Err(Error::msg("Device port, address and any only work with the sequencer backend")),
+            _ => Ok(())
+        }
+    }
+}
+
+impl Config {
+    pub fn default_path() -> PathBuf {
+        let base = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+            .unwrap_or_else(|| PathBuf::from("."));
+        base.join("blinkenpad").join("config.toml")
+    }
+
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Reading config {}", path.display()))?;
+        Config::parse(&text).with_context(|| format!("In config {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Config> {
+        let mut config: Config = toml::from_str(text)?;
+        if !config.plugins.is_empty() {
+            let plugins = std::mem::take(&mut config.plugins);
+            config.pages.insert(0, PageConfig {
+                name: "main".to_string(),
+                background: Background::Run,
+                plugins
+            });
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /*
+     * The frame left on the pad on exit, drawn as up to eight rows of characters:
+     * . off, r red, o orange, a amber, y yellow, g green.
+     */
+    pub fn goodbye(&self) -> Vec<(PadLocation, PadColour)> {
+        let mut result = Vec::new();
+        for (y, row) in self.goodbye.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                if let Some(colour) = Config::goodbye_colour(c) {
+                    result.push((PadLocation::on_pad(x as u8, y as u8), colour));
+                }
+            }
+        }
+        result
+    }
+
+    fn goodbye_colour(c: char) -> Option<PadColour> {
+        match c {
+            '.' | ' ' => Some(PadColour::new(0, 0)),
+            'r' => Some(PadColour::new(3, 0)),
+            'o' => Some(PadColour::new(3, 1)),
+            'a' => Some(PadColour::new(3, 3)),
+            'y' => Some(PadColour::new(2, 3)),
+            'g' => Some(PadColour::new(0, 3)),
+            _ => None
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.goodbye.len() > 8 || self.goodbye.iter().any(|row| row.chars().count() > 8) {
+            return Err(Error::msg("Goodbye frame is larger than the pad"));
+        }
+        if let Some(c) = self.goodbye.iter().flat_map(|row| row.chars())
+            .find(|c| Config::goodbye_colour(*c).is_none()) {
+            return Err(Error::msg(format!("Unknown colour '{}' in goodbye frame", c)));
+        }
+        self.device.validate()?;
+        if let Some(percent) = self.brightness {
+            if percent == 0 || percent > 100 {
+                return Err(Error::msg(format!("Brightness {} out of range 1-100", percent)));
+            }
+        }
+        if self.pages.is_empty() {
+            return Err(Error::msg("No plugins or pages configured"));
+        }
+        if self.pages.len() > 7 {
+            return Err(Error::msg("At most 7 pages are supported"));
+        }
+        for (i, page) in self.pages.iter().enumerate() {
+            if self.pages[..i].iter().any(|p| p.name == page.name) {
+                return Err(Error::msg(format!("Page {} is defined twice", page.name)));
+            }
+            page.validate().with_context(|| format!("Page {}", page.name))?;
+        }
+        let names: Vec<&String> = self.pages.iter().flat_map(|p| &p.plugins)
+            .filter_map(|p| p.name.as_ref())
+            .collect();
+        for (i, name) in names.iter().enumerate() {
+            if names[..i].contains(name) {
+                return Err(Error::msg(format!("Plugin name {} is used twice", name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PageConfig {
+    fn validate(&self) -> Result<()> {
+        for (i, plugin) in self.plugins.iter().enumerate() {
+            plugin.validate().with_context(|| format!("Plugin {}", i))?;
+            for other in &self.plugins[..i] {
+                if plugin.overlaps(other) {
+                    return Err(Error::msg(format!("Plugin {} overlaps an earlier plugin", i)));
+                }
+                if plugin.letters && other.letters && plugin.shares_rows(other) {
+                    return Err(Error::msg(format!("Plugin {} claims letters of an earlier plugin", i)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PluginConfig {
+    fn validate(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(Error::msg("Plugin area is empty"));
+        }
+        if self.x as u32 + self.width as u32 > 8 || self.y as u32 + self.height as u32 > 8 {
+            return Err(Error::msg("Plugin area is outside the pad"));
+        }
+        // Only these do anything with the Letters; the rest only have grid cells.
+        if self.letters && !matches!(self.kind, PluginKind::Mixer { .. } | PluginKind::Remote | PluginKind::Loopback) {
+            return Err(Error::msg("Only mixer, remote and loopback plugins can have letters"));
+        }
+        // Their layouts are fixed: transport and tracks, or playback and capture levels.
+        if let PluginKind::Mpris | PluginKind::Mixer { .. } = self.kind {
+            if self.width != 8 || self.height != 2 {
+                return Err(Error::msg("Mpris and mixer plugins must be 8 wide and 2 high"));
+            }
+        }
+        if let PluginKind::Xdo { keys } = &self.kind {
+            self.validate_cells(keys, |k| (k.x, k.y))?;
+            for key in keys {
+                key.action()?;
+                key.colour.validate()?;
+            }
+        }
+        if let PluginKind::Remote = self.kind {
+            if self.name.is_none() {
+                return Err(Error::msg("Remote plugin needs a name"));
+            }
+        }
+        if let PluginKind::Midi { channel, cells, .. } = &self.kind {
+            self.validate_cells(cells, |c| (c.x, c.y))?;
+            for cell in cells {
+                cell.validate(*channel)?;
+            }
+        }
+        if let PluginKind::Dbus { cells } = &self.kind {
+            self.validate_cells(cells, |c| (c.x, c.y))?;
+            for cell in cells {
+                cell.validate()?;
+            }
+        }
+        if let PluginKind::Shell { cells } = &self.kind {
+            self.validate_cells(cells, |c| (c.x, c.y))?;
+            for cell in cells {
+                cell.validate()?;
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Checks that every cell is inside the plugin area, and that no two are in the same place.
+     */
+    fn validate_cells<T>(&self, cells: &[T], position: impl Fn(&T) -> (u8, u8)) -> Result<()> {
+        for (i, cell) in cells.iter().enumerate() {
+            let (x, y) = position(cell);
+            if x >= self.width || y >= self.height {
+                return Err(Error::msg(format!("Cell {} {} is outside the plugin area", x, y)));
+            }
+            if cells[..i].iter().any(|c| position(c) == (x, y)) {
+                return Err(Error::msg(format!("Cell {} {} is defined twice", x, y)));
+            }
+        }
+        Ok(())
+    }
+
+    fn shares_rows(&self, other: &PluginConfig) -> bool {
+        self.y < other.y + other.height && other.y < self.y + self.height
+    }
+
+    fn overlaps(&self, other: &PluginConfig) -> bool {
+        self.x < other.x + other.width && other.x < self.x + self.width && self.shares_rows(other)
+    }
+
+    /*
+     * Builds the plugin, taking over any of the ports it needs from the layout being replaced.
+     */
+    pub fn build(&self, ports: &mut MidiPorts) -> Result<Box<dyn PluginArea>> {
+        Ok(match &self.kind {
+            PluginKind::Xdo { keys } => Box::new(XdoPlugin::new(
+                keys.iter().map(|k| Ok(XdoKey {
+                    x: k.x,
+                    y: k.y,
+                    colour: k.colour.colour(),
+                    action: k.action()?
+                })).collect::<Result<_>>()?
+            )?),
+            PluginKind::Mpris => Box::new(MprisPlugin::new()?),
+            PluginKind::Mixer { device, playback, capture } =>
+                Box::new(MixerPlugin::new(device, playback, capture)?),
+            PluginKind::Midi { port, channel, cells } =>
+                Box::new(MidiPlugin::new(port, cells.iter().map(|c| c.cell(*channel)).collect(), ports)?),
+            PluginKind::Dbus { cells } =>
+                Box::new(DbusPlugin::new(cells.iter().map(|c| c.cell()).collect::<Result<_>>()?)?),
+            PluginKind::Shell { cells } => Box::new(ShellPlugin::new(cells.iter().map(|c| c.cell()).collect())),
+            PluginKind::Remote => Box::new(RemotePlugin::new()),
+            PluginKind::Loopback => Box::new(PadLoopback::new())
+        })
+    }
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> ConfigWatcher {
+        ConfigWatcher {
+            path: path.to_path_buf(),
+            modified: ConfigWatcher::modified(path)
+        }
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn changed(&mut self) -> bool {
+        let modified = ConfigWatcher::modified(&self.path);
+        if modified.is_some() && modified != self.modified {
+            self.modified = modified;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alsa::seq;
+    use super::*;
+
+    fn error(text: &str) -> String {
+        match Config::parse(text) {
+            Ok(_) => panic!("Config was accepted:\n{}", text),
+            Err(e) => format!("{:#}", e)
+        }
+    }
+
+    fn loopback(page: &str) -> String {
+        format!("[[pages]]\nname = \"{}\"\n\
+                 [[pages.plugins]]\ntype = \"loopback\"\nx = 0\ny = 0\nwidth = 1\nheight = 1\n", page)
+    }
+
+    #[test]
+    fn top_level_plugins_go_on_main() {
+        let config = Config::parse(r#"
+            [[plugins]]
+            type = "loopback"
+            x = 0
+            y = 0
+            width = 8
+            height = 8
+        "#).unwrap();
+        assert_eq!(config.pages.len(), 1);
+        assert_eq!(config.pages[0].name, "main");
+        assert!(error("").contains("No plugins or pages configured"));
+    }
+
+    #[test]
+    fn plugin_area_on_the_pad() {
+        let plugin = |x: u8, y: u8, width: u8, height: u8| format!(
+            "[[plugins]]\ntype = \"loopback\"\nx = {}\ny = {}\nwidth = {}\nheight = {}\n", x, y, width, height);
+        assert!(Config::parse(&plugin(7, 7, 1, 1)).is_ok());
+        assert!(error(&plugin(7, 0, 2, 1)).contains("Plugin area is outside the pad"));
+        assert!(error(&plugin(0, 4, 1, 5)).contains("Plugin area is outside the pad"));
+        assert!(error(&plugin(255, 0, 255, 1)).contains("Plugin area is outside the pad"));
+        assert!(error(&plugin(0, 0, 0, 1)).contains("Plugin area is empty"));
+    }
+
+    #[test]
+    fn cells_inside_the_plugin() {
+        let shell = |cells: &str| format!(
+            "[[plugins]]\ntype = \"shell\"\nx = 4\ny = 4\nwidth = 2\nheight = 2\ncells = [{}]\n", cells);
+        assert!(Config::parse(&shell(r#"{ x = 1, y = 1, command = "true", colour = "red" }"#)).is_ok());
+        assert!(error(&shell(r#"{ x = 2, y = 0, command = "true", colour = "red" }"#))
+            .contains("Cell 2 0 is outside the plugin area"));
+        assert!(error(&shell(r#"{ x = 0, y = 2, command = "true", colour = "red" }"#))
+            .contains("Cell 0 2 is outside the plugin area"));
+        assert!(error(&shell(r#"{ x = 1, y = 0, command = "true", colour = "red" },
+                                { x = 1, y = 0, command = "false", colour = "red" }"#))
+            .contains("Cell 1 0 is defined twice"));
+    }
+
+    #[test]
+    fn plugins_dont_overlap() {
+        let two = |second_x: u8| format!(
+            "[[plugins]]\ntype = \"loopback\"\nx = 0\ny = 0\nwidth = 4\nheight = 2\n\
+             [[plugins]]\ntype = \"loopback\"\nx = {}\ny = 1\nwidth = 4\nheight = 2\n", second_x);
+        assert!(Config::parse(&two(4)).is_ok());
+        assert!(error(&two(3)).contains("Plugin 1 overlaps an earlier plugin"));
+    }
+
+    #[test]
+    fn letters_only_once_a_row() {
+        let two = |letters: bool, second_y: u8| format!(
+            "[[plugins]]\ntype = \"loopback\"\nx = 0\ny = 0\nwidth = 4\nheight = 2\nletters = true\n\
+             [[plugins]]\ntype = \"loopback\"\nx = 4\ny = {}\nwidth = 4\nheight = 2\nletters = {}\n",
+            second_y, letters);
+        assert!(Config::parse(&two(false, 1)).is_ok());
+        assert!(Config::parse(&two(true, 2)).is_ok());
+        assert!(error(&two(true, 1)).contains("Plugin 1 claims letters of an earlier plugin"));
+    }
+
+    #[test]
+    fn letters_only_for_plugins_that_use_them() {
+        assert!(error(r#"
+            [[plugins]]
+            type = "shell"
+            x = 0
+            y = 0
+            width = 1
+            height = 1
+            letters = true
+        "#).contains("Only mixer, remote and loopback plugins can have letters"));
+    }
+
+    #[test]
+    fn mpris_and_mixer_are_8_by_2() {
+        for kind in ["mpris", "mixer"] {
+            let plugin = |width: u8, height: u8| format!(
+                "[[plugins]]\ntype = \"{}\"\nx = 0\ny = 0\nwidth = {}\nheight = {}\n", kind, width, height);
+            assert!(Config::parse(&plugin(8, 2)).is_ok());
+            assert!(error(&plugin(8, 1)).contains("Mpris and mixer plugins must be 8 wide and 2 high"));
+            assert!(error(&plugin(4, 2)).contains("Mpris and mixer plugins must be 8 wide and 2 high"));
+        }
+    }
+
+    #[test]
+    fn brightness_in_range() {
+        let config = |percent: u32| format!("brightness = {}\n{}", percent, loopback("main"));
+        assert_eq!(Config::parse(&config(1)).unwrap().brightness, Some(1));
+        assert_eq!(Config::parse(&config(100)).unwrap().brightness, Some(100));
+        assert!(error(&config(0)).contains("Brightness 0 out of range 1-100"));
+        assert!(error(&config(101)).contains("Brightness 101 out of range 1-100"));
+    }
+
+    #[test]
+    fn at_most_seven_pages() {
+        let pages = |n: usize| (0..n).map(|i| loopback(&format!("page {}", i))).collect::<String>();
+        assert_eq!(Config::parse(&pages(7)).unwrap().pages.len(), 7);
+        assert!(error(&pages(8)).contains("At most 7 pages are supported"));
+    }
+
+    #[test]
+    fn names_are_unique() {
+        assert!(error(&(loopback("one") + &loopback("one"))).contains("Page one is defined twice"));
+        let named = |page: &str| format!("{}name = \"lights\"\n", loopback(page));
+        assert!(error(&(named("one") + &named("two"))).contains("Plugin name lights is used twice"));
+    }
+
+    #[test]
+    fn device_strings() {
+        let device = |lines: &str| format!("[device]\n{}\n{}", lines, loopback("main"));
+        let config = Config::parse(&device("client = \"Launchpad Mini\"\naddress = \"24:0\"")).unwrap();
+        let matcher = config.device.matcher().unwrap();
+        assert_eq!(matcher.address, Some(seq::Addr { client: 24, port: 0 }));
+        assert!(error(&device("client = \"Launch(pad\"")).contains("Device client"));
+        assert!(error(&device("port = \"[\"")).contains("Device port"));
+        assert!(error(&device("address = \"24\"")).contains("Address 24 is not client:port"));
+        assert!(error(&device("address = \"x:0\"")).contains("Bad client in address x:0"));
+        assert!(error(&device("address = \"24:y\"")).contains("Bad port in address 24:y"));
+        assert!(error(&device("any = true\nclient = \"Launchpad\""))
+            .contains("Device any can't be used with client, port or address"));
+        assert!(error(&device("rawmidi = \"hw:1,0\"")).contains("Device rawmidi needs the rawmidi backend"));
+        assert!(Config::parse(&device("backend = \"rawmidi\"\nrawmidi = \"hw:1,0\"")).is_ok());
+        assert!(error(&device("backend = \"rawmidi\"\nany = true"))
+            .contains("Device port, address and any only work with the sequencer backend"));
+        assert!(error(&device("backend = \"alsa\"")).contains("unknown variant"));
+    }
+
+    #[test]
+    fn goodbye_frame_fits() {
+        let goodbye = |rows: &str| format!("goodbye = [{}]\n{}", rows, loopback("main"));
+        let config = Config::parse(&goodbye(r#""r.g""#)).unwrap();
+        assert_eq!(config.goodbye(), vec![
+            (PadLocation::on_pad(0, 0), PadColour::new(3, 0)),
+            (PadLocation::on_pad(1, 0), PadColour::new(0, 0)),
+            (PadLocation::on_pad(2, 0), PadColour::new(0, 3))
+        ]);
+        assert!(error(&goodbye(r#""r........""#)).contains("Goodbye frame is larger than the pad"));
+        assert!(error(&goodbye(&[r#""r""#; 9].join(", "))).contains("Goodbye frame is larger than the pad"));
+        assert!(error(&goodbye(r#""x""#)).contains("Unknown colour 'x' in goodbye frame"));
+    }
+}
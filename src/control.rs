@@ -0,0 +1,322 @@
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::io::{ self, Read, Write };
+use std::os::unix::fs::{ FileTypeExt, PermissionsExt };
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{ UnixListener, UnixStream };
+use std::path::{ Path, PathBuf };
+use alsa::poll::{ pollfd, Flags };
+use anyhow::{ Result, Error, Context };
+use serde::Deserialize;
+use serde_json::{ json, Value, Map };
+use crate::blinken::BlinkenPad;
+use crate::config::ColourConfig;
+use crate::events::PadEvent;
+use crate::launchpad::PadLocation;
+
+/*
+ * A request from a client, one JSON object a line, e.g.
+ *
+ *   {"command": "set", "plugin": "status", "cells": [{"x": 0, "y": 0, "colour": "red"}]}
+ *
+ * Each is answered with a line holding "ok", and "error" if it failed.
+ */
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+enum Request {
+    // Lights cells of a remote plugin. Cells without a colour are turned off.
+    Set {
+        plugin: String,
+        cells: Vec<CellRequest>
+    },
+    Clear {
+        plugin: String
+    },
+    // The pages, the plugins and everything lit on the pad.
+    State,
+    Page {
+        name: String
+    },
+    Enable {
+        plugin: String
+    },
+    Disable {
+        plugin: String
+    },
+    // Sends every press, release, long press and double tap from then on.
+    Subscribe
+}
+
+/*
+ * A cell of a plugin, at x and y or at one of its letters.
+ */
+#[derive(Deserialize)]
+struct CellRequest {
+    x: Option<u8>,
+    y: Option<u8>,
+    letter: Option<u8>,
+    colour: Option<ColourConfig>,
+    #[serde(default)]
+    flash: bool
+}
+
+impl CellRequest {
+    // Not checked here; the plugin checks that the cell is inside it.
+    fn location(&self) -> Result<PadLocation> {
+        match (self.x, self.y, self.letter) {
+            (Some(x), Some(y), None) => Ok(PadLocation::OnPad(x, y)),
+            (None, None, Some(letter)) => Ok(PadLocation::Letters(letter)),
+            _ => Err(Error::msg("A cell needs either x and y, or letter"))
+        }
+    }
+}
+
+// Clients that send longer lines, or don't read what they are sent, are dropped.
+const MAX_BUFFER: usize = 1 << 16;
+
+struct Client {
+    stream: UnixStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    subscribed: bool,
+    closed: bool
+}
+
+impl Client {
+    fn new(stream: UnixStream) -> Client {
+        Client {
+            stream,
+            input: Vec::new(),
+            output: Vec::new(),
+            subscribed: false,
+            closed: false
+        }
+    }
+
+    /*
+     * Whole lines that have come in.
+     */
+    fn read_lines(&mut self) -> Vec<String> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                },
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => {
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+        let mut lines = Vec::new();
+        while let Some(end) = self.input.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.input.drain(..=end).collect();
+            lines.push(String::from_utf8_lossy(&line).trim().to_string());
+        }
+        if self.input.len() > MAX_BUFFER {
+            self.closed = true;
+        }
+        lines
+    }
+
+    fn send(&mut self, value: &Value) {
+        self.output.extend(value.to_string().bytes());
+        self.output.push(b'\n');
+        if self.output.len() > MAX_BUFFER {
+            self.closed = true;
+        }
+    }
+
+    fn flush(&mut self) {
+        while !self.output.is_empty() && !self.closed {
+            match self.stream.write(&self.output) {
+                Ok(0) => self.closed = true,
+                Ok(n) => {
+                    self.output.drain(..n);
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => self.closed = true
+            }
+        }
+    }
+
+    fn handle(&mut self, blinken: &mut BlinkenPad, line: &str) -> Result<Value> {
+        let request: Request = serde_json::from_str(line)?;
+        match request {
+            Request::Set { plugin, cells } => {
+                let mut changes = Vec::new();
+                for cell in &cells {
+                    let colour = match &cell.colour {
+                        Some(colour) => {
+                            colour.validate()?;
+                            let colour = colour.colour();
+                            Some(if cell.flash { colour.flashing() } else { colour })
+                        },
+                        None => None
+                    };
+                    changes.push((cell.location()?, colour));
+                }
+                blinken.set_cells(&plugin, &changes)?;
+            },
+            Request::Clear { plugin } => blinken.clear_cells(&plugin)?,
+            Request::State => return Ok(ControlSocket::state(blinken)),
+            Request::Page { name } => blinken.show_page(&name)?,
+            Request::Enable { plugin } => blinken.set_enabled(&plugin, true)?,
+            Request::Disable { plugin } => blinken.set_enabled(&plugin, false)?,
+            Request::Subscribe => self.subscribed = true
+        }
+        Ok(json!({ "ok": true }))
+    }
+}
+
+/*
+ * Listens on a Unix socket for clients such as scripts and status bars, which can light cells
+ * of remote plugins, switch pages, turn plugins on and off, and follow presses. The protocol is
+ * JSON, a line at a time; see Request.
+ */
+pub struct ControlSocket {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Vec<Client>
+}
+
+impl ControlSocket {
+    /*
+     * $XDG_RUNTIME_DIR/blinkenpad.sock, if there is a runtime directory.
+     */
+    pub fn default_path() -> Option<PathBuf> {
+        env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join("blinkenpad.sock"))
+    }
+
+    pub fn bind(path: &Path) -> Result<ControlSocket> {
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::msg(format!("{} is in use; is Blinkenpad already running?", path.display())));
+        }
+        // A socket nobody answers on was left behind by an instance that didn't exit cleanly.
+        if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Listening on {}", path.display()))?;
+        listener.set_nonblocking(true)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        eprintln!("Listening on {}", path.display());
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+            listener,
+            clients: Vec::new()
+        })
+    }
+
+    pub fn poll_descriptors(&self) -> Vec<pollfd> {
+        let mut result = vec![pollfd { fd: self.listener.as_raw_fd(), events: Flags::IN.bits(), revents: 0 }];
+        for client in &self.clients {
+            // Output that didn't fit in the socket is written once there is room for it.
+            let events = if client.output.is_empty() { Flags::IN } else { Flags::IN | Flags::OUT };
+            result.push(pollfd { fd: client.stream.as_raw_fd(), events: events.bits(), revents: 0 });
+        }
+        result
+    }
+
+    /*
+     * Takes on new clients and answers whatever they have asked.
+     */
+    pub fn process(&mut self, blinken: &mut BlinkenPad) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => if stream.set_nonblocking(true).is_ok() {
+                    self.clients.push(Client::new(stream));
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("Control socket: {}", e);
+                    break;
+                }
+            }
+        }
+        for client in &mut self.clients {
+            for line in client.read_lines() {
+                if line.is_empty() {
+                    continue;
+                }
+                let response = match client.handle(blinken, &line) {
+                    Ok(response) => response,
+                    Err(e) => json!({ "ok": false, "error": format!("{:#}", e) })
+                };
+                client.send(&response);
+            }
+            client.flush();
+        }
+        self.clients.retain(|c| !c.closed);
+    }
+
+    /*
+     * Passes what happened on the pad on to subscribed clients.
+     */
+    pub fn broadcast(&mut self, events: &[PadEvent]) {
+        let events: Vec<Value> = events.iter().filter_map(ControlSocket::event).collect();
+        if events.is_empty() {
+            return;
+        }
+        for client in self.clients.iter_mut().filter(|c| c.subscribed) {
+            for event in &events {
+                client.send(event);
+            }
+            client.flush();
+        }
+        self.clients.retain(|c| !c.closed);
+    }
+
+    fn event(event: &PadEvent) -> Option<Value> {
+        let (kind, ticks) = match event {
+            PadEvent::Press(_) => ("press", None),
+            PadEvent::Release(_, ticks) => ("release", Some(*ticks)),
+            PadEvent::LongPress(_) => ("long_press", None),
+            PadEvent::DoubleTap(_) => ("double_tap", None),
+            // Sent every tick for every button held down, which is more than clients need.
+            PadEvent::Held(..) => return None
+        };
+        let mut value = json!({ "event": kind, "location": event.location().to_string() });
+        if let Some(ticks) = ticks {
+            value["ticks"] = json!(ticks);
+        }
+        Some(value)
+    }
+
+    fn state(blinken: &BlinkenPad) -> Value {
+        let plugins: Vec<Value> = blinken.plugins().iter().map(|p| json!({
+            "page": p.page,
+            "name": p.name,
+            "x": p.x,
+            "y": p.y,
+            "width": p.width,
+            "height": p.height,
+            "enabled": p.enabled,
+            "failed": p.failed
+        })).collect();
+        let lights: Map<String, Value> = blinken.lights().iter()
+            .map(|(loc, colour)| (loc.to_string(), json!(colour.to_string())))
+            .collect();
+        json!({
+            "ok": true,
+            "page": blinken.page(),
+            "pages": blinken.page_names(),
+            "plugins": plugins,
+            "lights": lights
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,514 @@
+extern crate dbus;
+
+use std::collections::HashMap;
+use std::time::Duration;
+use alsa::poll::{ pollfd, Flags };
+use dbus::Message;
+use dbus::arg::{ ArgType, PropMap, RefArg };
+use dbus::arg::messageitem::{ MessageItem, MessageItemArray, MessageItemDict };
+use dbus::blocking::Connection;
+use dbus::channel::{ BusType, Channel };
+use dbus::message::MessageType;
+use dbus::strings::{ BusName, Interface, Member, Path, Signature };
+use crate::blinken::{ PluginArea, grid_only };
+use crate::events::PadEvent;
+use crate::launchpad::{PadLocation, PadColour};
+use anyhow::{ Result, Error };
+
+/*
+ * Parses a method argument written as in dbus-send: "string:hello", "uint32:5", "boolean:true",
+ * "variant:int32:3", "array:string:a,b" or "dict:string:int32:a,1,b,2". Arrays and dicts of
+ * variants take "type:value" elements. Values in arrays and dicts can't hold commas.
+ */
+pub fn parse_arg(text: &str) -> Result<MessageItem> {
+    let (kind, rest) = text.split_once(':')
+        .ok_or_else(|| Error::msg(format!("Argument {} has no type", text)))?;
+    match kind {
+        "variant" => Ok(MessageItem::Variant(Box::new(parse_arg(rest)?))),
+        "array" => {
+            let (kind, values) = rest.split_once(':')
+                .ok_or_else(|| Error::msg(format!("Array {} has no element type", text)))?;
+            let items = split_values(values).into_iter()
+                .map(|v| parse_element(kind, v))
+                .collect::<Result<Vec<_>>>()?;
+            let array = MessageItemArray::new(items, array_signature(kind, &signature(kind)?)?)
+                .map_err(|e| Error::msg(format!("Array {}: {:?}", text, e)))?;
+            Ok(MessageItem::Array(array))
+        },
+        "dict" => {
+            let mut parts = rest.splitn(3, ':');
+            let (key, value, values) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(key), Some(value), Some(values)) => (key, value, values),
+                _ => return Err(Error::msg(format!("Dict {} needs key and value types", text)))
+            };
+            if key == "variant" {
+                return Err(Error::msg(format!("Dict {} can't have variant keys", text)));
+            }
+            let values = split_values(values);
+            if !values.len().is_multiple_of(2) {
+                return Err(Error::msg(format!("Dict {} has a key without a value", text)));
+            }
+            let items = values.chunks(2)
+                .map(|pair| Ok((parse_element(key, pair[0])?, parse_element(value, pair[1])?)))
+                .collect::<Result<Vec<_>>>()?;
+            let dict = MessageItemDict::new(items, signature(key)?, signature(value)?)
+                .map_err(|e| Error::msg(format!("Dict {}: {:?}", text, e)))?;
+            Ok(MessageItem::Dict(dict))
+        },
+        _ => parse_basic(kind, rest)
+    }
+}
+
+fn split_values(values: &str) -> Vec<&str> {
+    if values.is_empty() { Vec::new() } else { values.split(',').collect() }
+}
+
+fn parse_element(kind: &str, value: &str) -> Result<MessageItem> {
+    if kind == "variant" {
+        parse_arg(value).map(|item| MessageItem::Variant(Box::new(item)))
+    } else {
+        parse_basic(kind, value)
+    }
+}
+
+fn parse_basic(kind: &str, value: &str) -> Result<MessageItem> {
+    let number = |e: std::num::ParseIntError| Error::msg(format!("{}:{}: {}", kind, value, e));
+    Ok(match kind {
+        "string" => MessageItem::Str(value.to_string()),
+        "objpath" => MessageItem::ObjectPath(Path::new(value.to_string()).map_err(Error::msg)?),
+        "boolean" => MessageItem::Bool(match value {
+            "true" => true,
+            "false" => false,
+            _ => return Err(Error::msg(format!("boolean:{} is neither true nor false", value)))
+        }),
+        "byte" => MessageItem::Byte(value.parse().map_err(number)?),
+        "int16" => MessageItem::Int16(value.parse().map_err(number)?),
+        "uint16" => MessageItem::UInt16(value.parse().map_err(number)?),
+        "int32" => MessageItem::Int32(value.parse().map_err(number)?),
+        "uint32" => MessageItem::UInt32(value.parse().map_err(number)?),
+        "int64" => MessageItem::Int64(value.parse().map_err(number)?),
+        "uint64" => MessageItem::UInt64(value.parse().map_err(number)?),
+        "double" => MessageItem::Double(value.parse()
+            .map_err(|_| Error::msg(format!("double:{} isn't a number", value)))?),
+        _ => return Err(Error::msg(format!("Unknown argument type {}", kind)))
+    })
+}
+
+fn signature(kind: &str) -> Result<Signature<'static>> {
+    let code = match kind {
+        "string" => "s",
+        "objpath" => "o",
+        "boolean" => "b",
+        "byte" => "y",
+        "int16" => "n",
+        "uint16" => "q",
+        "int32" => "i",
+        "uint32" => "u",
+        "int64" => "x",
+        "uint64" => "t",
+        "double" => "d",
+        "variant" => "v",
+        _ => return Err(Error::msg(format!("Unknown argument type {}", kind)))
+    };
+    Ok(Signature::from(code))
+}
+
+fn array_signature(kind: &str, element: &Signature) -> Result<Signature<'static>> {
+    Signature::new(format!("a{}", element)).map_err(|e| Error::msg(format!("Array of {}: {}", kind, e)))
+}
+
+/*
+ * The text of a value read from D-Bus, looking inside variants. Containers have none.
+ */
+fn value_text(value: &dyn RefArg) -> Option<String> {
+    match value.arg_type() {
+        ArgType::Variant => value.as_iter()?.next().and_then(value_text),
+        ArgType::Boolean => value.as_i64().map(|b| (b != 0).to_string()),
+        ArgType::Double => value.as_f64().map(|d| d.to_string()),
+        ArgType::String | ArgType::ObjectPath | ArgType::Signature => value.as_str().map(|s| s.to_string()),
+        ArgType::UInt64 => value.as_u64().map(|n| n.to_string()),
+        _ => value.as_i64().map(|n| n.to_string())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DbusBus {
+    Session,
+    System
+}
+
+/*
+ * Where a cell's colour comes from: a property, read now and then and whenever it is said to
+ * change, or the first argument of a signal.
+ */
+pub enum DbusSource {
+    Property(String),
+    Signal(String)
+}
+
+/*
+ * Gives a cell a colour from a value on the bus. The value's text picks from values, and
+ * otherwise true, a number other than 0 or any other non-empty text lights the cell in its
+ * colour, and anything else in off.
+ */
+pub struct DbusBinding {
+    pub source: DbusSource,
+    pub path: String,
+    pub interface: String,
+    pub off: PadColour,
+    pub values: HashMap<String, PadColour>
+}
+
+/*
+ * A cell that calls a method when pressed.
+ */
+pub struct DbusCell {
+    pub x: u8,
+    pub y: u8,
+    pub bus: DbusBus,
+    pub destination: String,
+    pub path: String,
+    pub interface: String,
+    pub method: String,
+    pub args: Vec<MessageItem>,
+    pub colour: PadColour,
+    pub binding: Option<DbusBinding>
+}
+
+// What a method call we are waiting on the reply to was for, by index into cells.
+enum Pending {
+    Call(usize),
+    Get(usize)
+}
+
+// Properties are read again this often, in ticks, for services that don't say they changed. A
+// reply that takes longer than this is given up on.
+const REFRESH_TICKS: u32 = 50;
+
+/*
+ * Cells that call D-Bus methods, set up entirely in the config. Nothing waits for a reply;
+ * replies and signals are picked up as they come in.
+ */
+pub struct DbusPlugin {
+    cells: Vec<DbusCell>,
+    session: Option<Connection>,
+    system: Option<Connection>,
+    // The text of each bound cell's value, once known.
+    values: Vec<Option<String>>,
+    // By serial, with the tick each was sent on.
+    pending: HashMap<(DbusBus, u32), (Pending, u32)>,
+    refreshed: Option<u32>
+}
+
+impl DbusPlugin {
+    pub fn new(cells: Vec<DbusCell>) -> Result<DbusPlugin> {
+        let connect = |bus: DbusBus, kind: BusType| -> Result<Option<Connection>> {
+            if !cells.iter().any(|c| c.bus == bus) {
+                return Ok(None);
+            }
+            let mut channel = Channel::get_private(kind)?;
+            channel.set_watch_enabled(true);
+            Ok(Some(Connection::from(channel)))
+        };
+        let plugin = DbusPlugin {
+            session: connect(DbusBus::Session, BusType::Session)?,
+            system: connect(DbusBus::System, BusType::System)?,
+            values: vec![None; cells.len()],
+            cells,
+            pending: HashMap::new(),
+            refreshed: None
+        };
+        for cell in &plugin.cells {
+            if let Some(binding) = &cell.binding {
+                plugin.connection(cell.bus)?.add_match_no_cb(&DbusPlugin::match_rule(cell, binding))?;
+            }
+        }
+        Ok(plugin)
+    }
+
+    fn connection(&self, bus: DbusBus) -> Result<&Connection> {
+        match bus {
+            DbusBus::Session => self.session.as_ref(),
+            DbusBus::System => self.system.as_ref()
+        }.ok_or_else(|| Error::msg("No connection to the bus"))
+    }
+
+    fn match_rule(cell: &DbusCell, binding: &DbusBinding) -> String {
+        let (interface, member) = match &binding.source {
+            DbusSource::Property(_) => ("org.freedesktop.DBus.Properties", "PropertiesChanged"),
+            DbusSource::Signal(signal) => (binding.interface.as_str(), signal.as_str())
+        };
+        format!("type='signal',sender='{}',path='{}',interface='{}',member='{}'",
+                cell.destination, binding.path, interface, member)
+    }
+
+    fn send(&mut self, bus: DbusBus, msg: Message, pending: Pending, tick: u32) -> Result<()> {
+        let serial = self.connection(bus)?.channel().send(msg)
+            .map_err(|_| Error::msg("Couldn't send on the bus"))?;
+        self.pending.insert((bus, serial), (pending, tick));
+        Ok(())
+    }
+
+    fn call(&mut self, i: usize, tick: u32) -> Result<()> {
+        let cell = &self.cells[i];
+        let mut msg = Message::new_method_call(&cell.destination, &cell.path, &cell.interface, &cell.method)
+            .map_err(Error::msg)?;
+        msg.append_items(&cell.args);
+        self.send(cell.bus, msg, Pending::Call(i), tick)
+    }
+
+    /*
+     * Gives up on replies that haven't come in time. A property whose Get went unanswered is as
+     * unknown as one whose service isn't there, and is asked for again.
+     */
+    fn expire(&mut self, tick: u32) {
+        let (cells, values) = (&self.cells, &mut self.values);
+        self.pending.retain(|_, (pending, sent)| {
+            if tick < *sent + REFRESH_TICKS {
+                return true;
+            }
+            match pending {
+                Pending::Call(i) => eprintln!("No answer to {}.{}", cells[*i].interface, cells[*i].method),
+                Pending::Get(i) => values[*i] = None
+            }
+            false
+        });
+    }
+
+    /*
+     * Asks for the value of every bound property.
+     */
+    fn refresh(&mut self, tick: u32) -> Result<()> {
+        for i in 0..self.cells.len() {
+            let cell = &self.cells[i];
+            let property = match &cell.binding {
+                Some(DbusBinding { source: DbusSource::Property(property), path, interface, .. }) =>
+                    Message::new_method_call(&cell.destination, path, "org.freedesktop.DBus.Properties", "Get")
+                        .map_err(Error::msg)?
+                        .append2(interface.as_str(), property.as_str()),
+                _ => continue
+            };
+            if !self.pending.values().any(|(p, _)| matches!(p, Pending::Get(j) if *j == i)) {
+                self.send(cell.bus, property, Pending::Get(i), tick)?;
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Handles whatever has come in on a bus: replies to calls and Gets, and signals.
+     */
+    fn receive(&mut self, bus: DbusBus) -> Result<()> {
+        let messages: Vec<Message> = {
+            let channel = match bus {
+                DbusBus::Session => self.session.as_ref(),
+                DbusBus::System => self.system.as_ref()
+            }.map(|c| c.channel());
+            let channel = match channel {
+                Some(channel) => channel,
+                None => return Ok(())
+            };
+            channel.read_write(Some(Duration::from_secs(0)))
+                .map_err(|_| Error::msg("Lost the bus"))?;
+            std::iter::from_fn(|| channel.pop_message()).collect()
+        };
+        for msg in messages {
+            match msg.msg_type() {
+                MessageType::MethodReturn | MessageType::Error => {
+                    let pending = msg.get_reply_serial().and_then(|s| self.pending.remove(&(bus, s)))
+                        .map(|(pending, _)| pending);
+                    match pending {
+                        Some(Pending::Call(i)) if msg.msg_type() == MessageType::Error => {
+                            let cell = &self.cells[i];
+                            eprintln!("Calling {}.{} failed: {}", cell.interface, cell.method,
+                                      msg.read1::<&str>().unwrap_or("no reason given"));
+                        },
+                        // A service that isn't there has no value.
+                        Some(Pending::Get(i)) if msg.msg_type() == MessageType::Error => self.values[i] = None,
+                        Some(Pending::Get(i)) =>
+                            self.values[i] = msg.iter_init().get_refarg().and_then(|v| value_text(&v)),
+                        _ => {}
+                    }
+                },
+                MessageType::Signal => self.signal(bus, &msg),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn signal(&mut self, bus: DbusBus, msg: &Message) {
+        let path = msg.path();
+        let interface = msg.interface();
+        let member = msg.member();
+        for (cell, value) in self.cells.iter().zip(self.values.iter_mut()) {
+            let binding = match &cell.binding {
+                Some(binding) if cell.bus == bus && path.as_deref() == Some(binding.path.as_str()) => binding,
+                _ => continue
+            };
+            match &binding.source {
+                DbusSource::Property(property) if member.as_deref() == Some("PropertiesChanged") => {
+                    if let Ok((changed_interface, changed, invalidated)) = msg.read3::<&str, PropMap, Vec<String>>() {
+                        if changed_interface != binding.interface {
+                            continue;
+                        }
+                        if let Some(v) = changed.get(property) {
+                            *value = value_text(&v.0);
+                        } else if invalidated.contains(property) {
+                            *value = None;
+                        }
+                    }
+                },
+                DbusSource::Signal(signal) if member.as_deref() == Some(signal.as_str())
+                    && interface.as_deref() == Some(binding.interface.as_str()) => {
+                    *value = msg.iter_init().get_refarg().and_then(|v| value_text(&v));
+                },
+                _ => {}
+            }
+        }
+    }
+
+    fn cell_colour(cell: &DbusCell, value: &Option<String>) -> PadColour {
+        let binding = match &cell.binding {
+            Some(binding) => binding,
+            None => return cell.colour
+        };
+        let value = match value {
+            Some(value) => value,
+            None => return binding.off
+        };
+        if let Some(colour) = binding.values.get(value) {
+            return *colour;
+        }
+        let on = match value.parse::<f64>() {
+            Ok(n) => n != 0.0,
+            Err(_) => !value.is_empty() && value != "false"
+        };
+        if on { cell.colour } else { binding.off }
+    }
+}
+
+/*
+ * Checks the names a cell uses, so that mistakes show up when the config is loaded.
+ */
+pub fn validate_names(destination: &str, path: &str, interface: &str, member: &str) -> Result<()> {
+    BusName::new(destination).map_err(Error::msg)?;
+    Path::new(path).map_err(Error::msg)?;
+    Interface::new(interface).map_err(Error::msg)?;
+    Member::new(member).map_err(Error::msg)?;
+    Ok(())
+}
+
+impl PluginArea for DbusPlugin {
+    fn process_input(&mut self, tick: u32, events: &Vec<PadEvent>) -> Result<()> {
+        for event in events {
+            match event {
+                PadEvent::Press(PadLocation::OnPad(x, y)) => {
+                    if let Some(i) = self.cells.iter().position(|c| c.x == *x && c.y == *y) {
+                        self.call(i, tick)?;
+                    }
+                },
+                other => grid_only(other)?
+            }
+        }
+        for conn in [&self.session, &self.system].into_iter().flatten() {
+            conn.channel().flush();
+        }
+        Ok(())
+    }
+
+    fn process_output(&mut self, tick: u32) -> Result<Vec<(PadLocation, PadColour)>> {
+        self.expire(tick);
+        if self.refreshed.is_none_or(|t| tick >= t + REFRESH_TICKS) {
+            self.refreshed = Some(tick);
+            self.refresh(tick)?;
+        }
+        self.receive(DbusBus::Session)?;
+        self.receive(DbusBus::System)?;
+        Ok(self.cells.iter().zip(&self.values)
+            .map(|(cell, value)| (PadLocation::on_pad(cell.x, cell.y), DbusPlugin::cell_colour(cell, value)))
+            .collect())
+    }
+
+    fn poll_descriptors(&self) -> Result<Vec<pollfd>> {
+        Ok([&self.session, &self.system].into_iter().flatten()
+            .map(|conn| pollfd { fd: conn.channel().watch().fd, events: Flags::IN.bits(), revents: 0 })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sig(text: &str) -> String {
+        parse_arg(text).unwrap().signature().to_string()
+    }
+
+    #[test]
+    fn parse_basic_types() {
+        assert_eq!(parse_arg("string:hello").unwrap(), MessageItem::Str("hello".to_string()));
+        // Only the first colon separates the type.
+        assert_eq!(parse_arg("string:a:b").unwrap(), MessageItem::Str("a:b".to_string()));
+        assert_eq!(parse_arg("string:").unwrap(), MessageItem::Str(String::new()));
+        assert_eq!(parse_arg("objpath:/org/example").unwrap(),
+                   MessageItem::ObjectPath(Path::new("/org/example").unwrap()));
+        assert_eq!(parse_arg("boolean:true").unwrap(), MessageItem::Bool(true));
+        assert_eq!(parse_arg("boolean:false").unwrap(), MessageItem::Bool(false));
+        assert_eq!(parse_arg("byte:255").unwrap(), MessageItem::Byte(255));
+        assert_eq!(parse_arg("int16:-32768").unwrap(), MessageItem::Int16(-32768));
+        assert_eq!(parse_arg("uint16:65535").unwrap(), MessageItem::UInt16(65535));
+        assert_eq!(parse_arg("int32:-5").unwrap(), MessageItem::Int32(-5));
+        assert_eq!(parse_arg("uint32:5").unwrap(), MessageItem::UInt32(5));
+        assert_eq!(parse_arg("int64:-9000000000").unwrap(), MessageItem::Int64(-9000000000));
+        assert_eq!(parse_arg("uint64:18446744073709551615").unwrap(), MessageItem::UInt64(u64::MAX));
+        assert_eq!(parse_arg("double:0.5").unwrap(), MessageItem::Double(0.5));
+    }
+
+    #[test]
+    fn parse_containers() {
+        assert_eq!(parse_arg("variant:int32:3").unwrap(),
+                   MessageItem::Variant(Box::new(MessageItem::Int32(3))));
+        assert_eq!(sig("variant:variant:string:x"), "v");
+        assert_eq!(sig("array:string:a,b"), "as");
+        assert_eq!(sig("array:int32:"), "ai");
+        assert_eq!(sig("array:variant:int32:1,string:a"), "av");
+        assert_eq!(sig("dict:string:int32:a,1,b,2"), "a{si}");
+        assert_eq!(sig("dict:string:variant:a,int32:1,b,boolean:true"), "a{sv}");
+        assert_eq!(sig("dict:uint32:string:"), "a{us}");
+        match parse_arg("array:uint16:1,2,3").unwrap() {
+            MessageItem::Array(array) => assert_eq!(array.to_vec(), vec![
+                MessageItem::UInt16(1), MessageItem::UInt16(2), MessageItem::UInt16(3)
+            ]),
+            other => panic!("Not an array: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn reject_bad_arguments() {
+        for text in [
+            "hello",
+            "int8:1",
+            "byte:256",
+            "int16:32768",
+            "uint16:-1",
+            "int32:1.5",
+            "uint32:",
+            "int64:x",
+            "uint64:-1",
+            "double:one",
+            "boolean:yes",
+            "objpath:not a path",
+            "variant:5",
+            "array:string",
+            "array:int32:1,x",
+            "array:char:a",
+            "dict:string:a,1",
+            "dict:string:int32:a,1,b",
+            "dict:variant:string:int32:1,a",
+            "dict:string:int32:a,b"
+        ] {
+            assert!(parse_arg(text).is_err(), "{} was accepted", text);
+        }
+    }
+}
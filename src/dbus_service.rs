@@ -0,0 +1,246 @@
+extern crate dbus;
+
+use std::ffi::CString;
+use std::time::Duration;
+use alsa::poll::{ pollfd, Flags };
+use dbus::Message;
+use dbus::arg::ReadAll;
+use dbus::blocking::Connection;
+use dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply;
+use dbus::channel::{ BusType, Channel };
+use dbus::message::MessageType;
+use dbus::strings::ErrorName;
+use anyhow::{ Result, Error };
+use crate::blinken::BlinkenPad;
+use crate::events::PadEvent;
+use crate::launchpad::{ PadLocation, PadColour };
+
+const NAME: &str = "org.blinkenpad.Pad";
+const PATH: &str = "/org/blinkenpad/Pad";
+const INTERFACE: &str = "org.blinkenpad.Pad";
+const FAILED: &str = "org.blinkenpad.Pad.Error.Failed";
+
+// A plugin as ListPlugins returns it: page, name, x, y, width, height, enabled and failed.
+type PluginRow = (String, String, u8, u8, u8, u8, bool, bool);
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.blinkenpad.Pad">
+    <method name="SetCell">
+      <arg name="plugin" type="s" direction="in"/>
+      <arg name="x" type="y" direction="in"/>
+      <arg name="y" type="y" direction="in"/>
+      <arg name="colour" type="s" direction="in"/>
+    </method>
+    <method name="FlashRegion">
+      <arg name="plugin" type="s" direction="in"/>
+      <arg name="x" type="y" direction="in"/>
+      <arg name="y" type="y" direction="in"/>
+      <arg name="width" type="y" direction="in"/>
+      <arg name="height" type="y" direction="in"/>
+      <arg name="colour" type="s" direction="in"/>
+    </method>
+    <method name="Clear">
+      <arg name="plugin" type="s" direction="in"/>
+    </method>
+    <method name="SwitchPage">
+      <arg name="name" type="s" direction="in"/>
+    </method>
+    <method name="ListPages">
+      <arg name="pages" type="as" direction="out"/>
+      <arg name="current" type="s" direction="out"/>
+    </method>
+    <method name="ListPlugins">
+      <arg name="plugins" type="a(ssyyyybb)" direction="out"/>
+    </method>
+    <method name="SetEnabled">
+      <arg name="plugin" type="s" direction="in"/>
+      <arg name="enabled" type="b" direction="in"/>
+    </method>
+    <signal name="Pressed">
+      <arg name="area" type="s"/>
+      <arg name="x" type="y"/>
+      <arg name="y" type="y"/>
+    </signal>
+    <signal name="Released">
+      <arg name="area" type="s"/>
+      <arg name="x" type="y"/>
+      <arg name="y" type="y"/>
+      <arg name="ticks" type="u"/>
+    </signal>
+    <signal name="LongPressed">
+      <arg name="area" type="s"/>
+      <arg name="x" type="y"/>
+      <arg name="y" type="y"/>
+    </signal>
+    <signal name="DoubleTapped">
+      <arg name="area" type="s"/>
+      <arg name="x" type="y"/>
+      <arg name="y" type="y"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml" type="s" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#;
+
+/*
+ * Owns org.blinkenpad.Pad on the session bus, with the same operations as the control socket
+ * as methods on /org/blinkenpad/Pad, and presses sent out as signals.
+ */
+pub struct DbusService {
+    conn: Connection
+}
+
+impl DbusService {
+    pub fn connect() -> Result<DbusService> {
+        let mut channel = Channel::get_private(BusType::Session)?;
+        channel.set_watch_enabled(true);
+        let conn = Connection::from(channel);
+        if conn.request_name(NAME, false, false, true)? != RequestNameReply::PrimaryOwner {
+            return Err(Error::msg(format!("{} is taken; is Blinkenpad already running?", NAME)));
+        }
+        eprintln!("Serving {} on the session bus", NAME);
+        Ok(DbusService {
+            conn
+        })
+    }
+
+    pub fn poll_descriptor(&self) -> pollfd {
+        let watch = self.conn.channel().watch();
+        pollfd { fd: watch.fd, events: Flags::IN.bits(), revents: 0 }
+    }
+
+    /*
+     * Answers any method calls that have come in. Fails if the bus has gone away.
+     */
+    pub fn process(&mut self, blinken: &mut BlinkenPad) -> Result<()> {
+        let channel = self.conn.channel();
+        channel.read_write(Some(Duration::from_secs(0)))
+            .map_err(|_| Error::msg("Lost the session bus"))?;
+        while let Some(msg) = channel.pop_message() {
+            if msg.msg_type() != MessageType::MethodCall {
+                continue;
+            }
+            let reply = match DbusService::handle(blinken, &msg) {
+                Ok(reply) => reply,
+                Err(e) => match e.downcast_ref::<dbus::Error>() {
+                    Some(e) => DbusService::error(&msg, e.name().unwrap_or(FAILED), e.message().unwrap_or("")),
+                    None => DbusService::error(&msg, FAILED, &format!("{:#}", e))
+                }
+            };
+            if !msg.get_no_reply() {
+                let _ = channel.send(reply);
+            }
+        }
+        channel.flush();
+        Ok(())
+    }
+
+    /*
+     * The arguments of a method call, which the caller gets told off for if they are wrong.
+     */
+    fn args<T: ReadAll>(msg: &Message) -> Result<T> {
+        msg.read_all().map_err(|e| {
+            let text = e.message().unwrap_or("Wrong arguments");
+            dbus::Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", text).into()
+        })
+    }
+
+    fn error(msg: &Message, name: &str, text: &str) -> Message {
+        let text = CString::new(text.replace('\0', "")).unwrap_or_default();
+        msg.error(&ErrorName::from(name), &text)
+    }
+
+    fn handle(blinken: &mut BlinkenPad, msg: &Message) -> Result<Message> {
+        if msg.path().as_deref() != Some(PATH) {
+            return Ok(DbusService::error(msg, "org.freedesktop.DBus.Error.UnknownObject", "No such object"));
+        }
+        let interface = msg.interface();
+        let member = msg.member();
+        match (interface.as_deref(), member.as_deref().unwrap_or("")) {
+            (Some("org.freedesktop.DBus.Introspectable"), "Introspect") =>
+                return Ok(msg.method_return().append1(INTROSPECTION)),
+            (Some(INTERFACE) | None, "SetCell") => {
+                let (plugin, x, y, colour): (String, u8, u8, String) = DbusService::args(msg)?;
+                blinken.set_cells(&plugin, &[(PadLocation::OnPad(x, y), DbusService::colour(&colour)?)])?;
+            },
+            (Some(INTERFACE) | None, "FlashRegion") => {
+                let (plugin, x, y, width, height, colour): (String, u8, u8, u8, u8, String) = DbusService::args(msg)?;
+                let colour = DbusService::colour(&colour)?.map(|c| c.flashing());
+                let mut cells = Vec::new();
+                for j in y..y.saturating_add(height) {
+                    for i in x..x.saturating_add(width) {
+                        cells.push((PadLocation::OnPad(i, j), colour));
+                    }
+                }
+                blinken.set_cells(&plugin, &cells)?;
+            },
+            (Some(INTERFACE) | None, "Clear") => {
+                let (plugin,): (String,) = DbusService::args(msg)?;
+                blinken.clear_cells(&plugin)?;
+            },
+            (Some(INTERFACE) | None, "SwitchPage") => {
+                let (name,): (String,) = DbusService::args(msg)?;
+                blinken.show_page(&name)?;
+            },
+            (Some(INTERFACE) | None, "ListPages") =>
+                return Ok(msg.method_return().append2(blinken.page_names(), blinken.page().unwrap_or(""))),
+            (Some(INTERFACE) | None, "ListPlugins") => {
+                let plugins: Vec<PluginRow> = blinken.plugins().into_iter()
+                    .map(|p| (p.page, p.name.unwrap_or_default(), p.x, p.y, p.width, p.height, p.enabled, p.failed))
+                    .collect();
+                return Ok(msg.method_return().append1(plugins));
+            },
+            (Some(INTERFACE) | None, "SetEnabled") => {
+                let (plugin, enabled): (String, bool) = DbusService::args(msg)?;
+                blinken.set_enabled(&plugin, enabled)?;
+            },
+            _ => return Ok(DbusService::error(msg, "org.freedesktop.DBus.Error.UnknownMethod", "No such method"))
+        }
+        Ok(msg.method_return())
+    }
+
+    /*
+     * A colour as in the config, "#rrggbb" or a name, or "" for off.
+     */
+    fn colour(text: &str) -> Result<Option<PadColour>> {
+        if text.is_empty() {
+            return Ok(None);
+        }
+        PadColour::parse(text).map(Some).ok_or_else(|| Error::msg(format!("Unknown colour {}", text)))
+    }
+
+    /*
+     * Sends a signal for everything that happened on the pad. A location is given as an area,
+     * "pad", "letter" or "number", and x and y: letters are numbered down the y axis and
+     * numbers along the x axis, with the other coordinate 0.
+     */
+    pub fn broadcast(&self, events: &[PadEvent]) {
+        for event in events {
+            let (area, x, y) = match event.location() {
+                PadLocation::OnPad(x, y) => ("pad", *x, *y),
+                PadLocation::Letters(l) => ("letter", 0, *l),
+                PadLocation::Numbers(n) => ("number", *n, 0)
+            };
+            let signal = |name: &str| Message::new_signal(PATH, INTERFACE, name)
+                .map(|m| m.append3(area, x, y));
+            let msg = match event {
+                PadEvent::Press(_) => signal("Pressed"),
+                PadEvent::Release(_, ticks) => signal("Released").map(|m| m.append1(*ticks)),
+                PadEvent::LongPress(_) => signal("LongPressed"),
+                PadEvent::DoubleTap(_) => signal("DoubleTapped"),
+                // There is no signal for these; LongPressed already says a button is being held.
+                PadEvent::Held(..) => continue
+            };
+            if let Ok(msg) = msg {
+                let _ = self.conn.channel().send(msg);
+            }
+        }
+        self.conn.channel().flush();
+    }
+}
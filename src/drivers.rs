@@ -0,0 +1,510 @@
+use std::time::Duration;
+use crate::alsa_midi::Event;
+use crate::launchpad::{ PadColour, PadLocation };
+
+/*
+ * Universal Device Inquiry, which every Launchpad answers with its model in the family code.
+ */
+pub const DEVICE_INQUIRY: [u8; 6] = [0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7];
+
+// How long a port has to answer the Device Inquiry.
+pub const INQUIRY_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Novation's manufacturer id, as it appears in SysEx.
+const NOVATION: [u8; 3] = [0x00, 0x20, 0x29];
+
+/*
+ * What one Launchpad model speaks: which notes and controllers are which buttons, and how
+ * colours are sent.
+ */
+pub trait PadDriver {
+    fn name(&self) -> &'static str;
+
+    /*
+     * Anything to send before the first frame, e.g. to put the pad into the right layout.
+     */
+    fn setup(&mut self) -> Vec<Event> {
+        Vec::new()
+    }
+
+    fn encode(&mut self, values: &[(PadLocation, PadColour)]) -> Vec<Event>;
+
+    /*
+     * Sets how bright the whole pad is, from 1 to 100 percent. Models that can't dim ignore it.
+     */
+    fn brightness(&mut self, _percent: u8) -> Vec<Event> {
+        Vec::new()
+    }
+
+    /*
+     * The button an event is for, and whether it was pressed (true) or released (false).
+     */
+    fn decode(&self, event: &Event) -> Option<(PadLocation, bool)>;
+}
+
+/*
+ * Picks the driver for a Device Inquiry reply, or None if it isn't from a Launchpad we know.
+ */
+pub fn driver_for(reply: &[u8]) -> Option<Box<dyn PadDriver>> {
+    if reply.len() < 10 || reply[..2] != [0xf0, 0x7e] || reply[3..5] != [0x06, 0x02]
+        || reply[5..8] != NOVATION {
+        return None;
+    }
+    match (reply[8], reply[9]) {
+        (0x20, 0x00) | (0x36, 0x00) => Some(Box::new(Mk1::new())),
+        (0x69, 0x00) => Some(Box::new(Mk2)),
+        (0x13, 0x01) => Some(Box::new(Mk3 { name: "Launchpad Mini Mk3", device: 0x0d })),
+        (0x03, 0x01) => Some(Box::new(Mk3 { name: "Launchpad X", device: 0x0c })),
+        _ => None
+    }
+}
+
+/*
+ * The Launchpad Mini Mk1 and Launchpad S: notes 16 to a row with the Letters in column 8, and
+ * controllers from 0x68 along the top. Colours are two-bit red and green in the velocity.
+ *
+ * The pad is kept double buffered. A few changes are written to both buffers at once, but a
+ * larger repaint is written to the hidden buffer with rapid update, which sets two LEDs per
+ * message, and then swapped in, so that it appears all at once.
+ *
+ * While anything flashes, the pad flips between the buffers by itself instead, and flashing
+ * LEDs are only lit in one of them. Repaints then go to both buffers and are no longer atomic.
+ */
+pub struct Mk1 {
+    // Velocities in rapid update order: the grid by rows, then the Letters, then the Numbers.
+    leds: [u8; 80],
+    flashing: [bool; 80],
+    // The buffer being shown; the other one is written to.
+    displayed: u8,
+    // Whether the pad is flipping between the buffers.
+    flash_mode: bool
+}
+
+// Repaints of more cells than this go through the hidden buffer. A full frame takes 42 messages.
+const MK1_RAPID_CELLS: usize = 16;
+
+// Velocity flags: write to both buffers, rather than just the one being updated.
+const MK1_COPY: u8 = 0x0c;
+// Velocity flags: write to the buffer being updated and blank the other, which flashes.
+const MK1_FLASH: u8 = 0x08;
+
+impl Mk1 {
+    pub fn new() -> Mk1 {
+        Mk1 {
+            leds: [0; 80],
+            flashing: [false; 80],
+            displayed: 0,
+            flash_mode: false
+        }
+    }
+
+    fn index(loc: &PadLocation) -> usize {
+        match loc {
+            PadLocation::OnPad(x, y) => (x + y * 8) as usize,
+            PadLocation::Letters(l) => 64 + *l as usize,
+            PadLocation::Numbers(n) => 72 + *n as usize
+        }
+    }
+
+    /*
+     * The buffer-control message, B0 00 xx, for showing one buffer and writing to the other.
+     */
+    fn buffers(&self, copy: bool) -> Event {
+        let update = 1 - self.displayed;
+        let copy = if copy { 0x10 } else { 0 };
+        Event::Control { channel: 0, param: 0, value: (0x20 | copy | (update << 2) | self.displayed).into() }
+    }
+
+    /*
+     * An LED's velocity with the flags for writing it straight to the pad.
+     */
+    fn velocity(&self, index: usize) -> u8 {
+        self.leds[index] | if self.flashing[index] { MK1_FLASH } else { MK1_COPY }
+    }
+
+    /*
+     * Writes the whole pad, two LEDs a message, to the buffer being updated.
+     */
+    fn rapid_update(&self, flags: bool) -> Vec<Event> {
+        // Selecting the X-Y layout again starts rapid update from the first LED.
+        let mut result = vec![Event::Control { channel: 0, param: 1, value: 0 }];
+        let velocities: Vec<u8> = (0..80)
+            .map(|i| if flags { self.velocity(i) } else { self.leds[i] })
+            .collect();
+        for pair in velocities.chunks(2) {
+            result.push(Event::Note { channel: 2, note: pair[0], velocity: pair[1] });
+        }
+        result
+    }
+}
+
+impl PadDriver for Mk1 {
+    fn name(&self) -> &'static str {
+        "Launchpad Mini"
+    }
+
+    fn setup(&mut self) -> Vec<Event> {
+        // Reset, which also turns every LED off.
+        self.leds = [0; 80];
+        self.flashing = [false; 80];
+        self.displayed = 0;
+        self.flash_mode = false;
+        vec![Event::Control { channel: 0, param: 0, value: 0 }, self.buffers(false)]
+    }
+
+    fn encode(&mut self, values: &[(PadLocation, PadColour)]) -> Vec<Event> {
+        for (loc, colour) in values {
+            self.leds[Mk1::index(loc)] = colour.mk1_velocity();
+            self.flashing[Mk1::index(loc)] = colour.is_flashing();
+        }
+        let flashing = self.flashing.iter().any(|f| *f);
+        let mut result = Vec::new();
+        if flashing && !self.flash_mode {
+            // Both buffers agree at this point, so only flashing LEDs will differ.
+            self.flash_mode = true;
+            self.displayed = 0;
+            result.push(Event::Control { channel: 0, param: 0, value: 0x28 });
+        }
+
+        if values.len() <= MK1_RAPID_CELLS {
+            for (loc, _) in values {
+                let velocity = self.velocity(Mk1::index(loc));
+                result.push(match loc {
+                    PadLocation::OnPad(x, y) => Event::Note { channel: 0, note: x + y * 16, velocity },
+                    PadLocation::Letters(l) => Event::Note { channel: 0, note: l * 16 + 8, velocity },
+                    PadLocation::Numbers(n) => Event::Control { channel: 0, param: 0x68 + *n as u32, value: velocity.into() }
+                });
+            }
+        } else if self.flash_mode {
+            result.append(&mut self.rapid_update(true));
+        } else {
+            result.append(&mut self.rapid_update(false));
+            // Show the new frame, and copy it into the other buffer so that both agree again.
+            self.displayed = 1 - self.displayed;
+            result.push(self.buffers(true));
+        }
+
+        if !flashing && self.flash_mode {
+            // Everything has been written to both buffers again, so stop flipping.
+            self.flash_mode = false;
+            self.displayed = 0;
+            result.push(self.buffers(false));
+        }
+        result
+    }
+
+    /*
+     * Sets the LED duty cycle, which the pad takes as a fraction: here n/16 for n from 1 to 16.
+     */
+    fn brightness(&mut self, percent: u8) -> Vec<Event> {
+        let n = ((percent.clamp(1, 100) as u32 * 16 + 50) / 100).max(1);
+        let denominator = 16 - 3;
+        vec![if n < 9 {
+            Event::Control { channel: 0, param: 0x1e, value: (((n - 1) << 4) | denominator) as i32 }
+        } else {
+            Event::Control { channel: 0, param: 0x1f, value: (((n - 9) << 4) | denominator) as i32 }
+        }]
+    }
+
+    fn decode(&self, event: &Event) -> Option<(PadLocation, bool)> {
+        match event {
+            Event::Note { .. } | Event::NoteOff { .. } => {
+                let (note, down) = event.note_state()?;
+                let x = note % 16;
+                let y = note >> 4;
+                let loc = match x {
+                    0..=7 => PadLocation::on_pad(x, y),
+                    8 => PadLocation::letter(y),
+                    _ => return None
+                };
+                Some((loc, down))
+            },
+            Event::Control { param, value, .. } if (0x68..0x70).contains(param) =>
+                Some((PadLocation::number((param - 0x68) as u8), *value > 0)),
+            _ => None
+        }
+    }
+}
+
+/*
+ * The RGB models number their grid in tens from the bottom left: 11 to 88, with the right hand
+ * column at 19 to 89.
+ */
+fn grid_index(loc: &PadLocation) -> Option<u8> {
+    match loc {
+        PadLocation::OnPad(x, y) => Some((8 - y) * 10 + x + 1),
+        PadLocation::Letters(l) => Some((8 - l) * 10 + 9),
+        PadLocation::Numbers(_) => None
+    }
+}
+
+fn grid_location(index: u8) -> Option<PadLocation> {
+    let (row, column) = (index / 10, index % 10);
+    match (row, column) {
+        (1..=8, 1..=8) => Some(PadLocation::on_pad(column - 1, 8 - row)),
+        (1..=8, 9) => Some(PadLocation::letter(8 - row)),
+        _ => None
+    }
+}
+
+/*
+ * Builds one SysEx message that sets many LEDs at once: the header, then each LED's bytes.
+ */
+fn led_sysex(header: &[u8], leds: Vec<Vec<u8>>) -> Vec<Event> {
+    if leds.is_empty() {
+        return Vec::new();
+    }
+    let mut data = header.to_vec();
+    for led in leds {
+        data.extend(led);
+    }
+    data.push(0xf7);
+    vec![Event::SysEx(data)]
+}
+
+/*
+ * The Launchpad Mk2 in its session layout. The grid and the right hand column are notes, the
+ * top row is controllers 104 to 111, and colours are sent as 6-bit RGB.
+ */
+pub struct Mk2;
+
+impl PadDriver for Mk2 {
+    fn name(&self) -> &'static str {
+        "Launchpad Mk2"
+    }
+
+    fn setup(&mut self) -> Vec<Event> {
+        vec![Event::SysEx(vec![0xf0, 0x00, 0x20, 0x29, 0x02, 0x18, 0x22, 0x00, 0xf7])]
+    }
+
+    fn encode(&mut self, values: &[(PadLocation, PadColour)]) -> Vec<Event> {
+        let led = |loc: &PadLocation| match loc {
+            PadLocation::Numbers(n) => 104 + n,
+            _ => grid_index(loc).unwrap_or(0)
+        };
+        let solid = values.iter().filter(|(_, colour)| !colour.is_flashing()).map(|(loc, colour)| {
+            let (r, g, b) = colour.components();
+            vec![led(loc), r >> 2, g >> 2, b >> 2]
+        }).collect();
+        // Flashing only works with palette colours.
+        let flashing = values.iter().filter(|(_, colour)| colour.is_flashing())
+            .map(|(loc, colour)| vec![led(loc), colour.palette_index()])
+            .collect();
+        let mut result = led_sysex(&[0xf0, 0x00, 0x20, 0x29, 0x02, 0x18, 0x0b], solid);
+        result.append(&mut led_sysex(&[0xf0, 0x00, 0x20, 0x29, 0x02, 0x18, 0x23], flashing));
+        result
+    }
+
+    fn decode(&self, event: &Event) -> Option<(PadLocation, bool)> {
+        match event {
+            Event::Note { .. } | Event::NoteOff { .. } => {
+                let (note, down) = event.note_state()?;
+                grid_location(note).map(|loc| (loc, down))
+            },
+            Event::Control { param, value, .. } if (104..112).contains(param) =>
+                Some((PadLocation::number((param - 104) as u8), *value > 0)),
+            _ => None
+        }
+    }
+}
+
+/*
+ * The Launchpad Mini Mk3 and Launchpad X in programmer mode. The grid is notes, the right hand
+ * column and the top row (91 to 98) are controllers, and colours are sent as 7-bit RGB. The two
+ * only differ in the device id in their SysEx.
+ */
+pub struct Mk3 {
+    name: &'static str,
+    device: u8
+}
+
+impl PadDriver for Mk3 {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn setup(&mut self) -> Vec<Event> {
+        vec![Event::SysEx(vec![0xf0, 0x00, 0x20, 0x29, 0x02, self.device, 0x0e, 0x01, 0xf7])]
+    }
+
+    fn encode(&mut self, values: &[(PadLocation, PadColour)]) -> Vec<Event> {
+        let leds = values.iter().map(|(loc, colour)| {
+            let led = match loc {
+                PadLocation::Numbers(n) => 91 + n,
+                _ => grid_index(loc).unwrap_or(0)
+            };
+            if colour.is_flashing() {
+                // Lighting type 1 flashes between two palette colours.
+                return vec![0x01, led, 0, colour.palette_index()];
+            }
+            let (r, g, b) = colour.components();
+            // Lighting type 3 is RGB.
+            vec![0x03, led, r >> 1, g >> 1, b >> 1]
+        }).collect();
+        led_sysex(&[0xf0, 0x00, 0x20, 0x29, 0x02, self.device, 0x03], leds)
+    }
+
+    fn brightness(&mut self, percent: u8) -> Vec<Event> {
+        let level = (percent.clamp(1, 100) as u32 * 127 / 100) as u8;
+        vec![Event::SysEx(vec![0xf0, 0x00, 0x20, 0x29, 0x02, self.device, 0x08, level, 0xf7])]
+    }
+
+    fn decode(&self, event: &Event) -> Option<(PadLocation, bool)> {
+        match event {
+            Event::Note { .. } | Event::NoteOff { .. } => {
+                let (note, down) = event.note_state()?;
+                match grid_location(note) {
+                    Some(PadLocation::Letters(_)) | None => None,
+                    Some(loc) => Some((loc, down))
+                }
+            },
+            Event::Control { param, value, .. } => match *param {
+                91..=98 => Some((PadLocation::number((param - 91) as u8), *value > 0)),
+                19..=89 if param % 10 == 9 =>
+                    grid_location(*param as u8).map(|loc| (loc, *value > 0)),
+                _ => None
+            },
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(channel: u8, note: u8, velocity: u8) -> Event {
+        Event::Note { channel, note, velocity }
+    }
+
+    fn control(param: u32, value: i32) -> Event {
+        Event::Control { channel: 0, param, value }
+    }
+
+    fn mk1() -> Mk1 {
+        let mut driver = Mk1::new();
+        // Reset, then show buffer 0 and write to buffer 1.
+        assert_eq!(driver.setup(), vec![control(0, 0), control(0, 0x24)]);
+        driver
+    }
+
+    fn grid(colour: PadColour) -> Vec<(PadLocation, PadColour)> {
+        (0..64).map(|i| (PadLocation::on_pad(i % 8, i / 8), colour)).collect()
+    }
+
+    #[test]
+    fn mk1_small_updates_go_to_both_buffers() {
+        let mut driver = mk1();
+        assert_eq!(driver.encode(&[
+            (PadLocation::on_pad(1, 2), PadColour::new(3, 0)),
+            (PadLocation::letter(3), PadColour::new(0, 3)),
+            (PadLocation::number(2), PadColour::new(3, 3))
+        ]), vec![note(0, 0x21, 0x0f), note(0, 0x38, 0x3c), control(0x6a, 0x3f)]);
+    }
+
+    #[test]
+    fn mk1_rapid_update_above_the_threshold() {
+        let mut driver = mk1();
+        let values = grid(PadColour::new(3, 0));
+        assert_eq!(driver.encode(&values[..MK1_RAPID_CELLS]).len(), MK1_RAPID_CELLS);
+
+        // Every LED is written, two at a time.
+        let result = driver.encode(&values[..MK1_RAPID_CELLS + 1]);
+        let mut expected = vec![control(1, 0)];
+        expected.extend((0..8).map(|_| note(2, 0x03, 0x03)));
+        expected.push(note(2, 0x03, 0));
+        expected.extend((0..31).map(|_| note(2, 0, 0)));
+        // Show buffer 1, write to buffer 0 and copy into it.
+        expected.push(control(0, 0x31));
+        assert_eq!(result, expected);
+
+        // The next frame is written to the other buffer and swapped back.
+        let mut expected = vec![control(1, 0)];
+        expected.extend((0..32).map(|_| note(2, 0x30, 0x30)));
+        expected.extend((0..8).map(|_| note(2, 0, 0)));
+        expected.push(control(0, 0x34));
+        assert_eq!(driver.encode(&grid(PadColour::new(0, 3))), expected);
+    }
+
+    #[test]
+    fn mk1_flashing_flips_the_buffers() {
+        let mut driver = mk1();
+        let cell = PadLocation::on_pad(0, 0);
+        assert_eq!(driver.encode(&[(cell.clone(), PadColour::new(3, 0).flashing())]),
+                   vec![control(0, 0x28), note(0, 0x00, 0x0b)]);
+        // A full frame while flashing goes through rapid update, flags and all, without a swap.
+        let mut values = grid(PadColour::new(0, 3));
+        values[0].1 = PadColour::new(0, 3).flashing();
+        let result = driver.encode(&values);
+        assert_eq!(result.len(), 41);
+        assert_eq!(result[0], control(1, 0));
+        assert_eq!(result[1], note(2, 0x38, 0x3c));
+        assert_eq!(driver.encode(&[(cell, PadColour::new(0, 3))]),
+                   vec![note(0, 0x00, 0x3c), control(0, 0x24)]);
+    }
+
+    #[test]
+    fn mk1_decode() {
+        let driver = Mk1::new();
+        assert_eq!(driver.decode(&note(0, 0x21, 0x7f)), Some((PadLocation::on_pad(1, 2), true)));
+        assert_eq!(driver.decode(&note(0, 0x77, 0)), Some((PadLocation::on_pad(7, 7), false)));
+        assert_eq!(driver.decode(&Event::NoteOff { channel: 0, note: 0x58, velocity: 0x40 }),
+                   Some((PadLocation::letter(5), false)));
+        assert_eq!(driver.decode(&control(0x68, 0x7f)), Some((PadLocation::number(0), true)));
+        assert_eq!(driver.decode(&control(0x6f, 0)), Some((PadLocation::number(7), false)));
+        assert_eq!(driver.decode(&note(0, 0x09, 0x7f)), None);
+        assert_eq!(driver.decode(&control(0x70, 0x7f)), None);
+    }
+
+    #[test]
+    fn mk2_encode() {
+        assert_eq!(Mk2.encode(&[
+            (PadLocation::on_pad(0, 0), PadColour::rgb(255, 128, 0)),
+            (PadLocation::number(1), PadColour::new(3, 3).flashing())
+        ]), vec![
+            Event::SysEx(vec![0xf0, 0x00, 0x20, 0x29, 0x02, 0x18, 0x0b, 81, 63, 32, 0, 0xf7]),
+            Event::SysEx(vec![0xf0, 0x00, 0x20, 0x29, 0x02, 0x18, 0x23, 105, 13, 0xf7])
+        ]);
+        assert_eq!(Mk2.encode(&[]), vec![]);
+    }
+
+    #[test]
+    fn mk2_decode() {
+        assert_eq!(Mk2.decode(&note(0, 11, 0x7f)), Some((PadLocation::on_pad(0, 7), true)));
+        assert_eq!(Mk2.decode(&note(0, 88, 0)), Some((PadLocation::on_pad(7, 0), false)));
+        assert_eq!(Mk2.decode(&note(0, 19, 0x7f)), Some((PadLocation::letter(7), true)));
+        assert_eq!(Mk2.decode(&note(0, 89, 0x7f)), Some((PadLocation::letter(0), true)));
+        assert_eq!(Mk2.decode(&control(104, 0x7f)), Some((PadLocation::number(0), true)));
+        assert_eq!(Mk2.decode(&control(111, 0)), Some((PadLocation::number(7), false)));
+        assert_eq!(Mk2.decode(&note(0, 10, 0x7f)), None);
+        assert_eq!(Mk2.decode(&note(0, 91, 0x7f)), None);
+        assert_eq!(Mk2.decode(&control(103, 0x7f)), None);
+    }
+
+    #[test]
+    fn mk3_encode() {
+        let mut driver = Mk3 { name: "Launchpad Mini Mk3", device: 0x0d };
+        assert_eq!(driver.encode(&[
+            (PadLocation::letter(0), PadColour::rgb(255, 0, 0)),
+            (PadLocation::number(0), PadColour::rgb(0, 0, 255).flashing())
+        ]), vec![Event::SysEx(vec![0xf0, 0x00, 0x20, 0x29, 0x02, 0x0d, 0x03,
+                                   0x03, 89, 127, 0, 0,
+                                   0x01, 91, 0, 45,
+                                   0xf7])]);
+    }
+
+    #[test]
+    fn mk3_decode() {
+        let driver = Mk3 { name: "Launchpad X", device: 0x0c };
+        assert_eq!(driver.decode(&note(0, 11, 0x7f)), Some((PadLocation::on_pad(0, 7), true)));
+        assert_eq!(driver.decode(&note(0, 88, 0)), Some((PadLocation::on_pad(7, 0), false)));
+        // The right hand column is controllers, not notes.
+        assert_eq!(driver.decode(&note(0, 19, 0x7f)), None);
+        assert_eq!(driver.decode(&control(19, 0x7f)), Some((PadLocation::letter(7), true)));
+        assert_eq!(driver.decode(&control(89, 0)), Some((PadLocation::letter(0), false)));
+        assert_eq!(driver.decode(&control(91, 0x7f)), Some((PadLocation::number(0), true)));
+        assert_eq!(driver.decode(&control(98, 0x7f)), Some((PadLocation::number(7), true)));
+        assert_eq!(driver.decode(&control(99, 0x7f)), None);
+        assert_eq!(driver.decode(&control(18, 0x7f)), None);
+    }
+}
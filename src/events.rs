@@ -0,0 +1,227 @@
+use crate::launchpad::PadLocation;
+
+/*
+ * What happened to a button, as seen by plugins. Durations are in ticks.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum PadEvent {
+    Press(PadLocation),
+    Release(PadLocation, u32),
+    // Sent once, when a button has been held down for the long press threshold.
+    LongPress(PadLocation),
+    // Sent alongside the Press of a second tap that follows a short first tap.
+    DoubleTap(PadLocation),
+    // Sent once a tick while a button is down, with how long it has been down.
+    Held(PadLocation, u32)
+}
+
+impl PadEvent {
+    pub fn location(&self) -> &PadLocation {
+        match self {
+            PadEvent::Press(l) => l,
+            PadEvent::Release(l, _) => l,
+            PadEvent::LongPress(l) => l,
+            PadEvent::DoubleTap(l) => l,
+            PadEvent::Held(l, _) => l
+        }
+    }
+
+    pub fn with_location(&self, loc: PadLocation) -> PadEvent {
+        match self {
+            PadEvent::Press(_) => PadEvent::Press(loc),
+            PadEvent::Release(_, t) => PadEvent::Release(loc, *t),
+            PadEvent::LongPress(_) => PadEvent::LongPress(loc),
+            PadEvent::DoubleTap(_) => PadEvent::DoubleTap(loc),
+            PadEvent::Held(_, t) => PadEvent::Held(loc, *t)
+        }
+    }
+}
+
+struct Down {
+    location: PadLocation,
+    since: u32,
+    long: bool
+}
+
+/*
+ * Turns raw presses and releases from the pad into PadEvents.
+ */
+pub struct Gestures {
+    long_press: u32,
+    double_tap: u32,
+    down: Vec<Down>,
+    taps: Vec<(PadLocation, u32)>,
+    last_tick: u32
+}
+
+impl Gestures {
+    pub fn new(long_press: u32, double_tap: u32) -> Gestures {
+        Gestures {
+            long_press,
+            double_tap,
+            down: Vec::new(),
+            taps: Vec::new(),
+            last_tick: 0
+        }
+    }
+
+    pub fn set_thresholds(&mut self, long_press: u32, double_tap: u32) {
+        self.long_press = long_press;
+        self.double_tap = double_tap;
+    }
+
+    /*
+     * Forgets any buttons held down, e.g. when the pad goes away before they are released.
+     */
+    pub fn reset(&mut self) {
+        self.down.clear();
+        self.taps.clear();
+    }
+
+    pub fn process(&mut self, tick: u32, input: &[(PadLocation, bool)]) -> Vec<PadEvent> {
+        let mut result = Vec::new();
+        let double_tap = self.double_tap;
+        self.taps.retain(|(_, t)| tick - t <= double_tap);
+
+        for (loc, pressed) in input {
+            let down = self.down.iter().position(|d| d.location == *loc);
+            match (pressed, down) {
+                (true, None) => {
+                    result.push(PadEvent::Press(loc.clone()));
+                    if let Some(tap) = self.taps.iter().position(|(l, _)| l == loc) {
+                        self.taps.remove(tap);
+                        result.push(PadEvent::DoubleTap(loc.clone()));
+                    }
+                    self.down.push(Down { location: loc.clone(), since: tick, long: false });
+                },
+                (false, Some(i)) => {
+                    let down = self.down.remove(i);
+                    let held = tick - down.since;
+                    result.push(PadEvent::Release(loc.clone(), held));
+                    if !down.long {
+                        self.taps.push((loc.clone(), tick));
+                    }
+                },
+                // Repeated presses or releases we never saw pressed.
+                _ => {}
+            }
+        }
+
+        // Input can arrive several times in a tick, but held buttons are only reported once.
+        if tick == self.last_tick {
+            return result;
+        }
+        self.last_tick = tick;
+        for down in &mut self.down {
+            let held = tick - down.since;
+            if held == 0 {
+                continue;
+            }
+            result.push(PadEvent::Held(down.location.clone(), held));
+            if !down.long && held >= self.long_press {
+                down.long = true;
+                result.push(PadEvent::LongPress(down.location.clone()));
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG_PRESS: u32 = 5;
+    const DOUBLE_TAP: u32 = 3;
+
+    fn cell() -> PadLocation {
+        PadLocation::on_pad(1, 2)
+    }
+
+    fn press() -> Vec<(PadLocation, bool)> {
+        vec![(cell(), true)]
+    }
+
+    fn release() -> Vec<(PadLocation, bool)> {
+        vec![(cell(), false)]
+    }
+
+    #[test]
+    fn press_and_release() {
+        let mut gestures = Gestures::new(LONG_PRESS, DOUBLE_TAP);
+        assert_eq!(gestures.process(10, &press()), vec![PadEvent::Press(cell())]);
+        assert_eq!(gestures.process(11, &[]), vec![PadEvent::Held(cell(), 1)]);
+        assert_eq!(gestures.process(12, &release()), vec![PadEvent::Release(cell(), 2)]);
+        assert_eq!(gestures.process(13, &[]), vec![]);
+        // Releases of buttons that were never pressed, and repeated presses, are dropped.
+        assert_eq!(gestures.process(14, &release()), vec![]);
+        assert_eq!(gestures.process(20, &press()), vec![PadEvent::Press(cell())]);
+        assert_eq!(gestures.process(20, &press()), vec![]);
+    }
+
+    #[test]
+    fn held_once_a_tick() {
+        let mut gestures = Gestures::new(LONG_PRESS, DOUBLE_TAP);
+        gestures.process(10, &press());
+        assert_eq!(gestures.process(10, &[]), vec![]);
+        assert_eq!(gestures.process(12, &[]), vec![PadEvent::Held(cell(), 2)]);
+        let other = PadLocation::letter(0);
+        assert_eq!(gestures.process(12, &[(other.clone(), true)]), vec![PadEvent::Press(other.clone())]);
+        assert_eq!(gestures.process(13, &[]),
+                   vec![PadEvent::Held(cell(), 3), PadEvent::Held(other, 1)]);
+    }
+
+    #[test]
+    fn long_press_at_the_threshold() {
+        let mut gestures = Gestures::new(LONG_PRESS, DOUBLE_TAP);
+        gestures.process(10, &press());
+        assert_eq!(gestures.process(10 + LONG_PRESS - 1, &[]), vec![PadEvent::Held(cell(), LONG_PRESS - 1)]);
+        assert_eq!(gestures.process(10 + LONG_PRESS, &[]),
+                   vec![PadEvent::Held(cell(), LONG_PRESS), PadEvent::LongPress(cell())]);
+        // Only the once.
+        assert_eq!(gestures.process(11 + LONG_PRESS, &[]), vec![PadEvent::Held(cell(), LONG_PRESS + 1)]);
+        assert_eq!(gestures.process(12 + LONG_PRESS, &release()), vec![PadEvent::Release(cell(), LONG_PRESS + 2)]);
+        // A long press doesn't count as the first tap of a double tap.
+        assert_eq!(gestures.process(13 + LONG_PRESS, &press()), vec![PadEvent::Press(cell())]);
+    }
+
+    #[test]
+    fn double_tap_within_the_threshold() {
+        let mut gestures = Gestures::new(LONG_PRESS, DOUBLE_TAP);
+        gestures.process(10, &press());
+        gestures.process(11, &release());
+        assert_eq!(gestures.process(11 + DOUBLE_TAP, &press()),
+                   vec![PadEvent::Press(cell()), PadEvent::DoubleTap(cell())]);
+        gestures.process(12 + DOUBLE_TAP, &release());
+        // The second tap can be the first of another.
+        assert_eq!(gestures.process(13 + DOUBLE_TAP, &press()),
+                   vec![PadEvent::Press(cell()), PadEvent::DoubleTap(cell())]);
+    }
+
+    #[test]
+    fn no_double_tap_after_the_threshold() {
+        let mut gestures = Gestures::new(LONG_PRESS, DOUBLE_TAP);
+        gestures.process(10, &press());
+        gestures.process(11, &release());
+        assert_eq!(gestures.process(12 + DOUBLE_TAP, &press()), vec![PadEvent::Press(cell())]);
+    }
+
+    #[test]
+    fn double_tap_only_on_the_same_button() {
+        let mut gestures = Gestures::new(LONG_PRESS, DOUBLE_TAP);
+        gestures.process(10, &press());
+        gestures.process(11, &release());
+        let other = PadLocation::on_pad(2, 2);
+        assert_eq!(gestures.process(12, &[(other.clone(), true)]), vec![PadEvent::Press(other)]);
+    }
+
+    #[test]
+    fn reset_forgets_held_buttons() {
+        let mut gestures = Gestures::new(LONG_PRESS, DOUBLE_TAP);
+        gestures.process(10, &press());
+        gestures.reset();
+        assert_eq!(gestures.process(11, &[]), vec![]);
+        assert_eq!(gestures.process(12, &release()), vec![]);
+        assert_eq!(gestures.process(13, &press()), vec![PadEvent::Press(cell())]);
+    }
+}
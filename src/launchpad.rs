@@ -1,29 +1,13 @@
 
 use crate::alsa_midi;
 
+use std::fmt;
 use anyhow::Result;
-use alsa_midi::PadControl;
+use alsa::poll::pollfd;
+use alsa_midi::{ Event, PadControl };
+use crate::drivers::{ self, PadDriver };
 
-#[derive(Copy, Clone, PartialEq)]
-pub struct PadColour {
-    red: u8,
-    green: u8
-}
-
-impl PadColour {
-    fn to_velocity(&self) -> u8 {
-        self.red & 0x3 | ((self.green & 0x3) << 4)
-    }
-
-    pub fn new(red: u8, green: u8) -> PadColour {
-        assert!(red < 4);
-        assert!(green < 4);
-        PadColour {
-            red: red,
-            green: green
-        }
-    }
-}
+pub use crate::colour::PadColour;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum PadLocation {
@@ -49,77 +33,116 @@ impl PadLocation {
         assert!(n < 8);
         PadLocation::Numbers(n)
     }
+}
 
-    fn from_event(ev: &alsa_midi::Event) -> Option<PadLocation> {
-        match ev {
-            alsa_midi::Event::Note { note, velocity } => {
-                let x = note % 16;
-                let y = note >> 4;
-                if *velocity > 0 {
-                    Some(if x >= 8 {
-                        PadLocation::letter(y)
-                    } else {
-                        PadLocation::on_pad(x, y)
-                    })
-                } else {
-                    None
-                }
-            },
-            alsa_midi::Event::Control { param, value } => {
-                if *value > 0 {
-                    Some(PadLocation::number((param - 0x68).try_into().unwrap()))
-                } else {
-                    None
-                }
-            }
-        }
-    }
-
-    fn to_event(&self, colour: &PadColour) -> alsa_midi::Event {
+impl fmt::Display for PadLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            PadLocation::OnPad(x, y) => alsa_midi::Event::Note {
-                note: x + y * 16,
-                velocity: colour.to_velocity(),
-            },
-            PadLocation::Letters(l) => alsa_midi::Event::Note {
-                note: (l * 16) + 8,
-                velocity: colour.to_velocity()
-            },
-            PadLocation::Numbers(n) => alsa_midi::Event::Control {
-                param: (0x68 + n).try_into().unwrap(),
-                value: colour.to_velocity().try_into().unwrap()
-            },
+            PadLocation::OnPad(x, y) => write!(f, "pad {} {}", x, y),
+            PadLocation::Letters(l) => write!(f, "letter {}", l),
+            PadLocation::Numbers(n) => write!(f, "number {}", n)
         }
     }
 }
 
 pub trait PadArea {
     fn process_in(&mut self, set_values: Vec<(PadLocation, PadColour)>) -> Result<()>;
-    fn process_out(&mut self) -> Result<Vec<PadLocation>>;
+    fn process_out(&mut self) -> Result<Vec<(PadLocation, bool)>>;
+    fn poll_descriptors(&self) -> Result<Vec<pollfd>>;
+    fn set_brightness(&mut self, percent: u8) -> Result<()>;
+    // Whether the pad is there. Changes show up after process_out.
+    fn connected(&self) -> bool;
 }
 
-pub struct LaunchPadMini<'a> {
+/*
+ * A Launchpad of whichever model the driver is for.
+ */
+pub struct LaunchPad<'a> {
      pub alsa_seq: &'a mut dyn PadControl,
+     driver: Box<dyn PadDriver>,
+     // Whether the driver's setup still has to be sent.
+     setup: bool,
+     // Kept so that it can be sent again after setup.
+     brightness: Option<u8>,
+     connected: bool
 }
 
-impl PadArea for LaunchPadMini<'_> {
+impl PadArea for LaunchPad<'_> {
 
     fn process_in(&mut self, set_values: Vec<(PadLocation, PadColour)>) -> Result<()> {
-        let events = set_values.iter().map(|(x, y)| x.to_event(y)).collect();
+        let mut events = Vec::new();
+        if self.setup {
+            events.append(&mut self.driver.setup());
+            if let Some(percent) = self.brightness {
+                events.append(&mut self.driver.brightness(percent));
+            }
+            self.setup = false;
+        }
+        events.append(&mut self.driver.encode(&set_values));
         self.alsa_seq.process_in(events)
     }
 
-    fn process_out(&mut self) -> Result<Vec<PadLocation>> {
-        Ok(self.alsa_seq.process_out()?.iter()
-            .filter_map(|x| PadLocation::from_event(x)).collect())
+    fn process_out(&mut self) -> Result<Vec<(PadLocation, bool)>> {
+        let mut result = Vec::new();
+        for event in self.alsa_seq.process_out()? {
+            if let Event::SysEx(data) = &event {
+                if let Some(driver) = drivers::driver_for(data) {
+                    self.set_driver(driver);
+                }
+                continue;
+            }
+            result.extend(self.driver.decode(&event));
+        }
+        // A pad that has come back has forgotten everything, so it is set up again.
+        let connected = self.alsa_seq.connected();
+        if connected && !self.connected {
+            self.setup = true;
+        }
+        self.connected = connected;
+        Ok(result)
+    }
+
+    fn poll_descriptors(&self) -> Result<Vec<pollfd>> {
+        self.alsa_seq.poll_descriptors()
+    }
+
+    fn set_brightness(&mut self, percent: u8) -> Result<()> {
+        self.brightness = Some(percent);
+        if self.setup {
+            // It goes out with the setup.
+            return Ok(());
+        }
+        let events = self.driver.brightness(percent);
+        self.alsa_seq.process_in(events)
+    }
+
+    fn connected(&self) -> bool {
+        self.connected
     }
 
 }
 
-impl <'a> LaunchPadMini<'a> {
-    pub fn new(seq: &'a mut alsa_midi::AlsaSeq) -> LaunchPadMini<'a> {
-        LaunchPadMini {
+impl <'a> LaunchPad<'a> {
+    pub fn new(seq: &'a mut dyn PadControl, driver: Box<dyn PadDriver>) -> LaunchPad<'a> {
+        let connected = seq.connected();
+        LaunchPad {
+            connected,
             alsa_seq: seq,
+            driver,
+            setup: true,
+            brightness: None
+        }
+    }
+
+    /*
+     * Switches to another model's driver, e.g. when a different Launchpad answers the device
+     * inquiry.
+     */
+    fn set_driver(&mut self, driver: Box<dyn PadDriver>) {
+        if driver.name() != self.driver.name() {
+            eprintln!("Driving {}", driver.name());
         }
+        self.driver = driver;
+        self.setup = true;
     }
 }
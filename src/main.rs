@@ -1,66 +1,289 @@
 extern crate clap;
 extern crate anyhow;
+extern crate libc;
 
 mod alsa_midi;
 mod launchpad;
+mod colour;
 mod blinken;
 mod xdo_plugin;
 mod mpris_plugin;
 mod mixer_plugin;
+mod midi_plugin;
+mod remote_plugin;
+mod dbus_plugin;
+mod shell_plugin;
+mod config;
+mod events;
+mod signals;
+mod virtual_pad;
+mod drivers;
+mod record;
+mod rawmidi;
+mod control;
+mod dbus_service;
 
-use clap::App;
-use std::{ thread, time };
+use clap::{ App, Arg };
+use std::time::{ Duration, Instant };
+use alsa::poll::pollfd;
+use std::io;
+use std::path::{ Path, PathBuf };
 use anyhow::Result;
 use blinken::BlinkenPad;
-use launchpad::PadColour;
-use mixer_plugin::MixerPlugin;
-use mpris_plugin::MprisPlugin;
-use xdo_plugin::XdoPlugin;
+use config::{ Config, ConfigWatcher };
+use signals::{ Signals, SignalAction };
+use alsa_midi::PadControl;
+use config::{ DeviceConfig, MidiBackend };
+use rawmidi::RawMidiPad;
+use drivers::{ Mk1, PadDriver };
+use virtual_pad::VirtualPad;
+use record::Recorder;
+use control::ControlSocket;
+use dbus_service::DbusService;
 
 fn main() -> Result<()> {
-    let _matches = App::new("Blinkenpad")
+    let matches = App::new("Blinkenpad")
         .version("0.1.0")
         .author("Sunny Kalsi <thesunnyk@gmail.com>")
         .about("Blinkenlights and macropad on the Launchpad")
+        .arg(Arg::new("config")
+            .short('c')
+            .long("config")
+            .takes_value(true)
+            .help("Layout file (default $XDG_CONFIG_HOME/blinkenpad/config.toml)"))
+        .arg(Arg::new("pad")
+            .long("pad")
+            .takes_value(true)
+            .possible_values(["launchpad", "virtual", "headless"])
+            .default_value("launchpad")
+            .help("Use a real Launchpad, one drawn in the terminal, or one with no display at all"))
+        .arg(Arg::new("record")
+            .long("record")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write everything sent to and from the pad to FILE"))
+        .arg(Arg::new("replay")
+            .long("replay")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with("record")
+            .help("Play a recording back through the layout and report where the pad output differs"))
+        .arg(Arg::new("device-client")
+            .long("device-client")
+            .takes_value(true)
+            .value_name("REGEX")
+            .help("Only use a pad whose MIDI client name matches"))
+        .arg(Arg::new("device-port")
+            .long("device-port")
+            .takes_value(true)
+            .value_name("REGEX")
+            .help("Only use a pad whose MIDI port name matches"))
+        .arg(Arg::new("device-address")
+            .long("device-address")
+            .takes_value(true)
+            .value_name("CLIENT:PORT")
+            .help("Only use the pad on this MIDI port"))
+        .arg(Arg::new("device-any")
+            .long("device-any")
+            .conflicts_with_all(&["device-client", "device-port", "device-address"])
+            .help("Try every MIDI port, not just Launchpads"))
+        .arg(Arg::new("socket")
+            .long("socket")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Listen for control clients on PATH (default $XDG_RUNTIME_DIR/blinkenpad.sock)"))
         .get_matches();
 
-    let mut seq = alsa_midi::AlsaSeq::setup_alsaseq()?;
-    seq.connect_all()?;
-    let mut pad = launchpad::LaunchPadMini::new(&mut seq);
+    let config_path = matches.value_of("config").map(PathBuf::from)
+        .unwrap_or_else(Config::default_path);
+    let watcher = ConfigWatcher::new(&config_path);
+    let config = Config::load(&config_path)?;
 
-    let xdo = XdoPlugin::new(
-        vec![PadColour::new(0,3), PadColour::new(2,2), PadColour::new(1,2), PadColour::new(3,0),
-        PadColour::new(0,3)],
-        vec!["super".to_string(), "control+c".to_string(), "control+v".to_string(), "alt+a".to_string(),
-        "alt+v".to_string()]
-        )?;
-    let mpris = MprisPlugin::new()?;
-    let mixer = MixerPlugin::mixer()?;
-    let mixer_plugin = MixerPlugin::init(&mixer)?;
+    if let Some(path) = matches.value_of("replay") {
+        if record::replay(Path::new(path), &config)? > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-    let mut blinken = BlinkenPad::new(&mut pad);
-    blinken.add_plugin(0, 7, 5, 1, Box::new(xdo));
-    blinken.add_plugin(0, 5, 8, 2, Box::new(mpris));
-    blinken.add_plugin(0, 3, 8, 2, Box::new(mixer_plugin));
+    let mut seq;
+    let mut raw;
+    let mut virtual_pad;
+    // The virtual pad is a Mini Mk1; a real one says what it is.
+    let mut driver: Box<dyn PadDriver> = Box::new(Mk1::new());
+    let control: &mut dyn PadControl = match matches.value_of("pad") {
+        Some("virtual") => {
+            virtual_pad = VirtualPad::new(true)?;
+            &mut virtual_pad
+        },
+        Some("headless") => {
+            virtual_pad = VirtualPad::new(false)?;
+            &mut virtual_pad
+        },
+        _ => {
+            let mut device = config.device.clone();
+            for (arg, field) in [("device-client", &mut device.client), ("device-port", &mut device.port),
+                                 ("device-address", &mut device.address)] {
+                if let Some(value) = matches.value_of(arg) {
+                    *field = Some(value.to_string());
+                    device.any = false;
+                }
+            }
+            if matches.is_present("device-any") {
+                device = DeviceConfig { any: true, client: None, port: None, address: None, ..device };
+            }
+            let (control, reply): (&mut dyn PadControl, _) = match device.backend {
+                MidiBackend::Sequencer => {
+                    seq = alsa_midi::AlsaSeq::setup_alsaseq(device.matcher()?)?;
+                    let reply = seq.connect_all()?;
+                    (&mut seq, reply)
+                },
+                MidiBackend::Rawmidi => {
+                    raw = RawMidiPad::new(device.rawmidi.clone(), device.card()?);
+                    let reply = raw.connect()?;
+                    (&mut raw, reply)
+                }
+            };
+            if let Some(found) = reply.and_then(|reply| drivers::driver_for(&reply)) {
+                driver = found;
+            }
+            control
+        }
+    };
+    let mut pad = launchpad::LaunchPad::new(control, driver);
+
+    let control = match matches.value_of("socket").map(PathBuf::from).or_else(ControlSocket::default_path) {
+        Some(path) => Some(ControlSocket::bind(&path)?),
+        None => {
+            eprintln!("No $XDG_RUNTIME_DIR, so no control socket");
+            None
+        }
+    };
+
+    // Without a session bus, e.g. on a console, everything else still works.
+    let dbus = match DbusService::connect() {
+        Ok(service) => Some(service),
+        Err(e) => {
+            eprintln!("No D-Bus service: {:#}", e);
+            None
+        }
+    };
 
-    blink(blinken)?;
+    let signals = Signals::new()?;
+    let recorder = matches.value_of("record").map(|path| Recorder::create(Path::new(path))).transpose()?;
+
+    // Nothing that can fail comes between loading and blink, which shuts the plugins down again.
+    let mut blinken = BlinkenPad::new(&mut pad);
+    blinken.load(&config)?;
+    if let Some(recorder) = recorder {
+        blinken.set_recorder(recorder);
+    }
+    blink(blinken, watcher, signals, control, dbus)?;
 
     Ok(())
 }
 
-fn blink(mut blinken: BlinkenPad) -> Result<()> {
+fn reload(blinken: &mut BlinkenPad, path: &Path) {
+    match Config::load(path).and_then(|config| blinken.load(&config)) {
+        Ok(()) => eprintln!("Reloaded {}", path.display()),
+        Err(e) => {
+            eprintln!("Keeping previous layout: {:#}", e);
+            blinken.show_error();
+        }
+    }
+}
+
+fn blink(mut blinken: BlinkenPad, mut watcher: ConfigWatcher, mut signals: Signals,
+         mut control: Option<ControlSocket>, mut dbus: Option<DbusService>) -> Result<()> {
 
     blinken.clear_pad()?;
 
-    loop {
-        if blinken.process_all()? {
-            break;
-        }
-        thread::sleep(time::Duration::from_millis(100));
+    // However the loop ends, leave the pad tidy.
+    let result = run(&mut blinken, &mut watcher, &mut signals, &mut control, &mut dbus);
+    if let Err(e) = &result {
+        eprintln!("Stopping: {:#}", e);
     }
 
     blinken.cleanup();
 
     blinken.clear_pad()?;
+    blinken.show_goodbye()?;
+    result
+}
+
+/*
+ * Waits for any of the descriptors, up to the timeout. A signal arriving just ends the wait.
+ */
+fn wait(fds: &mut [pollfd], timeout: Duration) -> Result<()> {
+    // Round up, or the last part of a millisecond before a tick turns into a busy loop.
+    let millis = timeout.as_micros().div_ceil(1000);
+    let r = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, millis as i32) };
+    if r < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e.into());
+        }
+    }
     Ok(())
 }
+
+// The config file is checked for changes this often, in ticks.
+const WATCH_TICKS: u32 = 10;
+
+fn run(blinken: &mut BlinkenPad, watcher: &mut ConfigWatcher, signals: &mut Signals,
+       control: &mut Option<ControlSocket>, dbus: &mut Option<DbusService>) -> Result<()> {
+    // Input wakes us straight away; the tick only drives animations and timing.
+    let tick = Duration::from_millis(100);
+    let mut next_tick = Instant::now() + tick;
+    let mut ticks = 0u32;
+    loop {
+        let mut fds = blinken.poll_descriptors()?;
+        fds.push(signals.poll_descriptor());
+        if let Some(control) = control {
+            fds.append(&mut control.poll_descriptors());
+        }
+        if let Some(service) = dbus {
+            fds.push(service.poll_descriptor());
+        }
+        let timeout = next_tick.saturating_duration_since(Instant::now());
+        wait(&mut fds, timeout)?;
+
+        for action in signals.pending() {
+            match action {
+                SignalAction::Quit => return Ok(()),
+                SignalAction::Reload => reload(blinken, watcher.path())
+            }
+        }
+
+        let ticked = Instant::now() >= next_tick;
+        if ticked {
+            next_tick += tick;
+            // Don't try to catch up after a stall, just carry on from now.
+            if next_tick < Instant::now() {
+                next_tick = Instant::now() + tick;
+            }
+            ticks += 1;
+        }
+        // Requests are answered first, so that what they change shows in this step.
+        if let Some(control) = control {
+            control.process(blinken);
+        }
+        if let Some(Err(e)) = dbus.as_mut().map(|service| service.process(blinken)) {
+            eprintln!("Stopping the D-Bus service: {:#}", e);
+            *dbus = None;
+        }
+        let quit = blinken.process_all(ticked)?;
+        if let Some(control) = control {
+            control.broadcast(blinken.events());
+        }
+        if let Some(service) = dbus {
+            service.broadcast(blinken.events());
+        }
+        if quit {
+            return Ok(());
+        }
+        if ticked && ticks.is_multiple_of(WATCH_TICKS) && watcher.changed() {
+            reload(blinken, watcher.path());
+        }
+    }
+}
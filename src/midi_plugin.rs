@@ -0,0 +1,206 @@
+use std::rc::Rc;
+use alsa::poll::pollfd;
+use crate::alsa_midi::{ Event, MidiPort };
+use crate::blinken::{ PluginArea, grid_only };
+use crate::events::PadEvent;
+use crate::launchpad::{PadLocation, PadColour};
+use anyhow::Result;
+
+/*
+ * What pressing a cell sends.
+ */
+#[derive(Clone, Copy, PartialEq)]
+pub enum MidiAction {
+    // Note on when pressed, note off when released.
+    Note { note: u8, velocity: u8 },
+    // Each press turns the controller on or off.
+    Toggle { cc: u8 },
+    // The controller is on while the cell is held.
+    Momentary { cc: u8 },
+    Program { program: u8 }
+}
+
+pub struct MidiCell {
+    pub x: u8,
+    pub y: u8,
+    pub channel: u8,
+    pub action: MidiAction,
+    pub colour: PadColour,
+    pub off: PadColour
+}
+
+/*
+ * The ports of the MIDI plugins in a layout being replaced, by name. A plugin in the new layout
+ * takes over one with its name rather than opening another, so that the connections other
+ * applications made to it aren't cut. Each is taken at most once.
+ */
+#[derive(Default)]
+pub struct MidiPorts {
+    ports: Vec<(String, Rc<MidiPort>)>
+}
+
+impl MidiPorts {
+    pub fn add(&mut self, name: &str, port: Rc<MidiPort>) {
+        self.ports.push((name.to_string(), port));
+    }
+
+    fn open(&mut self, name: &str) -> Result<Rc<MidiPort>> {
+        match self.ports.iter().position(|(n, _)| n == name) {
+            Some(i) => Ok(self.ports.remove(i).1),
+            None => Ok(Rc::new(MidiPort::open(name)?))
+        }
+    }
+}
+
+/*
+ * Turns cells into a MIDI controller, sending out of a sequencer port of its own. Cells are lit
+ * by what they last sent, or by what comes back in on the port, so that they follow the other
+ * end, e.g. a DAW.
+ */
+pub struct MidiPlugin {
+    name: String,
+    port: Rc<MidiPort>,
+    cells: Vec<MidiCell>,
+    // Whether each cell is on, by index into cells.
+    lit: Vec<bool>,
+    // Cells being held down, by index into cells.
+    down: Vec<usize>
+}
+
+impl MidiPlugin {
+    pub fn new(port: &str, cells: Vec<MidiCell>, ports: &mut MidiPorts) -> Result<MidiPlugin> {
+        Ok(MidiPlugin {
+            name: port.to_string(),
+            port: ports.open(port)?,
+            lit: vec![false; cells.len()],
+            cells,
+            down: Vec::new()
+        })
+    }
+
+    fn find_cell(&self, x: u8, y: u8) -> Option<usize> {
+        self.cells.iter().position(|c| c.x == x && c.y == y)
+    }
+
+    /*
+     * Shows what a message says about the cells, whether we sent it or it came in.
+     */
+    fn follow(&mut self, event: &Event) {
+        let note_state = event.note_state();
+        for (cell, lit) in self.cells.iter().zip(self.lit.iter_mut()) {
+            match (cell.action, event) {
+                (MidiAction::Note { note, .. }, Event::Note { channel, .. } | Event::NoteOff { channel, .. })
+                    if *channel == cell.channel => if let Some((n, down)) = note_state {
+                        if n == note {
+                            *lit = down;
+                        }
+                    },
+                (MidiAction::Toggle { cc } | MidiAction::Momentary { cc }, Event::Control { channel, param, value })
+                    if *channel == cell.channel && *param == cc as u32 => *lit = *value >= 64,
+                (MidiAction::Program { program }, Event::ProgramChange { channel, program: p })
+                    if *channel == cell.channel => *lit = *p == program,
+                _ => {}
+            }
+        }
+    }
+
+    fn press(&self, i: usize) -> Event {
+        let cell = &self.cells[i];
+        let channel = cell.channel;
+        match cell.action {
+            MidiAction::Note { note, velocity } => Event::Note { channel, note, velocity },
+            MidiAction::Toggle { cc } => {
+                let value = if self.lit[i] { 0 } else { 127 };
+                Event::Control { channel, param: cc as u32, value }
+            },
+            MidiAction::Momentary { cc } => Event::Control { channel, param: cc as u32, value: 127 },
+            MidiAction::Program { program } => Event::ProgramChange { channel, program }
+        }
+    }
+
+    fn release(&self, i: usize) -> Option<Event> {
+        let cell = &self.cells[i];
+        let channel = cell.channel;
+        match cell.action {
+            MidiAction::Note { note, .. } => Some(Event::Note { channel, note, velocity: 0 }),
+            MidiAction::Momentary { cc } => Some(Event::Control { channel, param: cc as u32, value: 0 }),
+            _ => None
+        }
+    }
+
+    /*
+     * Adds a message to those going out, lighting the cells as it says.
+     */
+    fn emit(&mut self, event: Event, out: &mut Vec<Event>) {
+        self.follow(&event);
+        out.push(event);
+    }
+
+    /*
+     * Lets go of cells that are held down, so that no note is left sounding when they can no
+     * longer be released.
+     */
+    fn release_all(&mut self) -> Result<()> {
+        let mut out = Vec::new();
+        for i in std::mem::take(&mut self.down) {
+            if let Some(event) = self.release(i) {
+                self.emit(event, &mut out);
+            }
+        }
+        self.port.send(&out)
+    }
+}
+
+impl PluginArea for MidiPlugin {
+    fn process_input(&mut self, _tick: u32, events: &Vec<PadEvent>) -> Result<()> {
+        let mut out = Vec::new();
+        for event in events {
+            match event {
+                PadEvent::Press(PadLocation::OnPad(x, y)) => if let Some(i) = self.find_cell(*x, *y) {
+                    let event = self.press(i);
+                    self.emit(event, &mut out);
+                    self.down.push(i);
+                },
+                PadEvent::Release(PadLocation::OnPad(x, y), _) => if let Some(i) = self.find_cell(*x, *y) {
+                    if self.down.contains(&i) {
+                        self.down.retain(|d| *d != i);
+                        if let Some(event) = self.release(i) {
+                            self.emit(event, &mut out);
+                        }
+                    }
+                },
+                other => grid_only(other)?
+            }
+        }
+        self.port.send(&out)
+    }
+
+    fn process_output(&mut self, _tick: u32) -> Result<Vec<(PadLocation, PadColour)>> {
+        for event in self.port.receive()? {
+            self.follow(&event);
+        }
+        Ok(self.cells.iter().zip(&self.lit).map(|(cell, lit)| {
+            (PadLocation::on_pad(cell.x, cell.y), if *lit { cell.colour } else { cell.off })
+        }).collect())
+    }
+
+    fn poll_descriptors(&self) -> Result<Vec<pollfd>> {
+        self.port.poll_descriptors()
+    }
+
+    fn hand_over(&self, ports: &mut MidiPorts) {
+        ports.add(&self.name, self.port.clone());
+    }
+
+    fn on_hide(&mut self) -> Result<()> {
+        self.release_all()
+    }
+
+    fn on_device_lost(&mut self) -> Result<()> {
+        self.release_all()
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        self.release_all()
+    }
+}
@@ -3,61 +3,97 @@ extern crate dbus;
 
 use anyhow::{ Result, Error };
 use alsa::mixer::{ Mixer, SelemId, Selem, SelemChannelId };
+use alsa::poll::{ Descriptors, pollfd };
 use crate::launchpad::{ PadColour, PadLocation};
 use crate::blinken::PluginArea;
+use crate::events::PadEvent;
 use std::rc::Rc;
 
-pub struct MixerPlugin<'a> {
-    mixer: &'a Mixer,
-    master: Selem<'a>,
-    capture: Selem<'a>
+pub struct MixerPlugin {
+    mixer: Mixer,
+    playback: String,
+    capture: String
 }
 
-impl <'a> MixerPlugin<'a> {
-    pub fn mixer() -> Result<Mixer> {
-        let mut mixer = Mixer::new("pulse", true)?;
-        // Selem::register(&mut mixer);
-        Ok(mixer)
+impl MixerPlugin {
+    pub fn new(device: &str, playback: &str, capture: &str) -> Result<MixerPlugin> {
+        let plugin = MixerPlugin {
+            mixer: Mixer::new(device, true)?,
+            playback: playback.to_string(),
+            capture: capture.to_string()
+        };
+        plugin.master()?;
+        plugin.capture()?;
+        Ok(plugin)
     }
 
-    pub fn init(mixer: &'a Mixer) -> Result<MixerPlugin<'a>> {
-        let master_id = SelemId::new("Master", 0);
-        let capture_id = SelemId::new("Capture", 0);
-
-        Ok(MixerPlugin {
-            mixer: &mixer,
-            master: mixer.find_selem(&master_id)
-                .ok_or(Error::msg("Could not get master control"))?,
-            capture: mixer.find_selem(&capture_id)
-                .ok_or(Error::msg("Could not get capture control"))?
-        })
+    fn master(&self) -> Result<Selem<'_>> {
+        self.mixer.find_selem(&SelemId::new(&self.playback, 0))
+            .ok_or(Error::msg("Could not get master control"))
+    }
+
+    fn capture(&self) -> Result<Selem<'_>> {
+        self.mixer.find_selem(&SelemId::new(&self.capture, 0))
+            .ok_or(Error::msg("Could not get capture control"))
+    }
+
+    fn switch_colour(on: bool) -> PadColour {
+        if on {
+            PadColour::new(0,2)
+        } else {
+            PadColour::new(3,0)
+        }
     }
 
 }
 
-impl PluginArea for MixerPlugin<'_> {
-    fn process_input(&mut self, tick: u32, set_values: &Vec<PadLocation>) -> Result<()> {
-        for val in set_values {
+impl PluginArea for MixerPlugin {
+    fn process_input(&mut self, tick: u32, events: &Vec<PadEvent>) -> Result<()> {
+        for event in events {
+            let val = match event {
+                PadEvent::Press(l) => l,
+                _ => continue
+            };
             match val {
                 PadLocation::OnPad(x,y) => {
                     match y {
                         0 => {
-                            let (play_min, play_max) = self.master.get_playback_volume_range();
+                            let master = self.master()?;
+                            let (play_min, play_max) = master.get_playback_volume_range();
                             let play_set =  ((*x as i64 + 1)* (play_max - play_min)) / 8;
 
-                            self.master.set_playback_volume_all(play_set)?;
+                            master.set_playback_volume_all(play_set)?;
                         },
                         1 => {
-                            let (cap_min, cap_max) = self.capture.get_capture_volume_range();
+                            let capture = self.capture()?;
+                            let (cap_min, cap_max) = capture.get_capture_volume_range();
                             let cap_set =  ((*x as i64 + 1) * (cap_max - cap_min)) / 8;
 
-                            self.capture.set_capture_volume(SelemChannelId::FrontLeft, cap_set)?;
+                            capture.set_capture_volume(SelemChannelId::FrontLeft, cap_set)?;
                         },
-                        _ => panic!("unknown button")
+                        _ => Err(Error::msg("Only supports two rows"))?
                     }
                 },
-                PadLocation::Letters(_) => panic!("Cannot handle letters yet"),
-                PadLocation::Numbers(_) => panic!("Cannot handle numbers"),
+                PadLocation::Letters(l) => {
+                    match l {
+                        0 => {
+                            let master = self.master()?;
+                            if master.has_playback_switch() {
+                                let on = master.get_playback_switch(SelemChannelId::FrontLeft)?;
+                                master.set_playback_switch_all(if on == 0 { 1 } else { 0 })?;
+                            }
+                        },
+                        1 => {
+                            let capture = self.capture()?;
+                            if capture.has_capture_switch() {
+                                let on = capture.get_capture_switch(SelemChannelId::FrontLeft)?;
+                                capture.set_capture_switch_all(if on == 0 { 1 } else { 0 })?;
+                            }
+                        },
+                        _ => Err(Error::msg("Only supports two letters"))?
+                    }
+                },
+                PadLocation::Numbers(_) => Err(Error::msg("Cannot handle numbers"))?,
             }
         }
 
@@ -67,11 +103,13 @@ impl PluginArea for MixerPlugin<'_> {
     fn process_output(&mut self, tick: u32) -> Result<Vec<(PadLocation, PadColour)>> {
         self.mixer.handle_events()?;
 
-        let (cap_min, cap_max) = self.capture.get_capture_volume_range();
-        let cap_cur = self.capture.get_capture_volume(SelemChannelId::FrontLeft)?;
+        let capture = self.capture()?;
+        let (cap_min, cap_max) = capture.get_capture_volume_range();
+        let cap_cur = capture.get_capture_volume(SelemChannelId::FrontLeft)?;
 
-        let (play_min, play_max) = self.master.get_playback_volume_range();
-        let play_cur = self.master.get_playback_volume(SelemChannelId::FrontLeft)?;
+        let master = self.master()?;
+        let (play_min, play_max) = master.get_playback_volume_range();
+        let play_cur = master.get_playback_volume(SelemChannelId::FrontLeft)?;
 
         let mut result = Vec::new();
 
@@ -94,7 +132,20 @@ impl PluginArea for MixerPlugin<'_> {
             result.push((PadLocation::OnPad(i, 1), cap_col));
         }
 
-        // TODO Add letter when it is supported
+        // Mute toggles: green while sound goes through, red when muted.
+        if master.has_playback_switch() {
+            let play_on = master.get_playback_switch(SelemChannelId::FrontLeft)? != 0;
+            result.push((PadLocation::Letters(0), MixerPlugin::switch_colour(play_on)));
+        }
+        if capture.has_capture_switch() {
+            let cap_on = capture.get_capture_switch(SelemChannelId::FrontLeft)? != 0;
+            result.push((PadLocation::Letters(1), MixerPlugin::switch_colour(cap_on)));
+        }
+
         Ok(result)
     }
+
+    fn poll_descriptors(&self) -> Result<Vec<pollfd>> {
+        Ok(self.mixer.get()?)
+    }
 }
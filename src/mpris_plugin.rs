@@ -2,23 +2,55 @@
 extern crate mpris;
 
 use std::time::Duration;
-use crate::blinken::PluginArea;
+use alsa::poll::{ pollfd, Flags };
+use dbus::blocking::Connection;
+use dbus::channel::{ BusType, Channel };
+use crate::blinken::{ PluginArea, grid_only };
+use crate::events::PadEvent;
 use crate::launchpad::{PadLocation, PadColour};
 use mpris::{ PlayerFinder, Player, PlaybackStatus, FindingError, DBusError, LoopStatus,
              MetadataValue, TrackID };
 use anyhow::{ Context, Result, Error };
 
 pub struct MprisPlugin {
-    player: Option<Player>
+    player: Option<Player>,
+    // Only used to wake the main loop when a player changes.
+    signals: Connection,
+    // The seek button being held down past a long press, which keeps seeking until released.
+    scrubbing: Option<u8>,
+    // While hidden the player isn't polled and its signals aren't listened to.
+    visible: bool
 }
 
+const PROPERTIES_MATCH: &str = "type='signal',interface='org.freedesktop.DBus.Properties',\
+    path='/org/mpris/MediaPlayer2'";
+
 impl MprisPlugin {
     pub fn new() -> Result<MprisPlugin> {
         Ok(MprisPlugin {
             player: None,
+            signals: MprisPlugin::signal_connection()?,
+            scrubbing: None,
+            visible: false
         })
     }
 
+    fn signal_connection() -> Result<Connection> {
+        let mut channel = Channel::get_private(BusType::Session)?;
+        channel.set_watch_enabled(true);
+        Ok(Connection::from(channel))
+    }
+
+    /*
+     * Throws away any signals that have arrived; the player is asked for its state directly.
+     */
+    fn drain_signals(&self) {
+        let channel = self.signals.channel();
+        if channel.read_write(Some(Duration::from_secs(0))).is_ok() {
+            while channel.pop_message().is_some() {}
+        }
+    }
+
     fn get_active_player() -> Result<Option<Player>> {
         let finder = PlayerFinder::new()?;
         finder.find_active().map(|p| Some(p)).or_else(|e| match e {
@@ -78,17 +110,16 @@ impl MprisPlugin {
         Ok(())
     }
 
-    fn render_controls(tick: u32, status: PlaybackStatus) -> Vec<(PadLocation, PadColour)> {
+    fn render_controls(status: PlaybackStatus) -> Vec<(PadLocation, PadColour)> {
         let mut result = Vec::new();
         // previous track
         result.push((PadLocation::on_pad(0, 0), PadColour::new(3,3)));
         // rewind
         result.push((PadLocation::on_pad(1, 0), PadColour::new(2,2)));
-        // playpause
-        let flash = if ((tick / 5) % 2) == 0 { 3u8 } else { 0u8};
+        // playpause, flashed by the pad itself
         let playpausecolour = match status {
-            PlaybackStatus::Playing => PadColour::new(0, flash),
-            PlaybackStatus::Paused => PadColour::new(flash, flash),
+            PlaybackStatus::Playing => PadColour::new(0, 3).flashing(),
+            PlaybackStatus::Paused => PadColour::new(3, 3).flashing(),
             PlaybackStatus::Stopped => PadColour::new(0, 3),
         };
         result.push((PadLocation::on_pad(2, 0), playpausecolour));
@@ -138,7 +169,7 @@ impl MprisPlugin {
     fn render_with_player(tick: u32, p: &Player) -> Result<Vec<(PadLocation, PadColour)>> {
         let mut result = Vec::new();
         let status = p.get_playback_status().context("Get playback status")?;
-        result.append(&mut MprisPlugin::render_controls(tick, status));
+        result.append(&mut MprisPlugin::render_controls(status));
         let mut tracker = p.track_progress(100)?;
 
         let progress = tracker.tick().progress;
@@ -148,19 +179,30 @@ impl MprisPlugin {
 }
 
 impl PluginArea for MprisPlugin {
-    fn process_input(&mut self, tick: u32, set_values: &Vec<PadLocation>) -> Result<()> {
+    fn process_input(&mut self, tick: u32, events: &Vec<PadEvent>) -> Result<()> {
+        for event in events {
+            match event {
+                PadEvent::LongPress(PadLocation::OnPad(x, 0)) if *x == 1 || *x == 6 =>
+                    self.scrubbing = Some(*x),
+                PadEvent::Release(PadLocation::OnPad(x, 0), _) if self.scrubbing == Some(*x) =>
+                    self.scrubbing = None,
+                _ => {}
+            }
+        }
+        let scrubbing = self.scrubbing;
         let active = self.refresh_active(tick).context("While refreshing")?;
         match active {
             Some(p) =>
-            for value in set_values {
-                match value {
-                    PadLocation::OnPad(x,y) => match y {
+            for event in events {
+                match event {
+                    PadEvent::Press(PadLocation::OnPad(x,y)) => match y {
                         0 => MprisPlugin::command(p, *x)?,
                         1 => MprisPlugin::seek(p, *x)?,
                         _ => Err(Error::msg("Only supports two lines"))?
                     },
-                    PadLocation::Letters(_) => panic!("Invalid letter pad press in plugin"),
-                    PadLocation::Numbers(_) => panic!("Invalid number pad press in plugin"),
+                    PadEvent::Held(PadLocation::OnPad(x, 0), held)
+                        if scrubbing == Some(*x) && held % 2 == 0 => MprisPlugin::command(p, *x)?,
+                    other => grid_only(other)?
                 }
             },
             None => ()
@@ -169,6 +211,10 @@ impl PluginArea for MprisPlugin {
     }
 
     fn process_output(&mut self, tick: u32) -> Result<Vec<(PadLocation, PadColour)>> {
+        if !self.visible {
+            return Ok(Vec::new());
+        }
+        self.drain_signals();
         let active = self.refresh_active(tick).context("While refreshing on output")?;
         match active {
             Some(p) => {
@@ -184,5 +230,31 @@ impl PluginArea for MprisPlugin {
             }
         }
     }
-}
 
+    fn poll_descriptors(&self) -> Result<Vec<pollfd>> {
+        if !self.visible {
+            return Ok(Vec::new());
+        }
+        let watch = self.signals.channel().watch();
+        Ok(vec![pollfd { fd: watch.fd, events: Flags::IN.bits(), revents: 0 }])
+    }
+
+    fn on_show(&mut self) -> Result<()> {
+        self.signals.add_match_no_cb(PROPERTIES_MATCH)?;
+        self.visible = true;
+        Ok(())
+    }
+
+    fn on_hide(&mut self) -> Result<()> {
+        self.visible = false;
+        self.scrubbing = None;
+        self.signals.remove_match_no_cb(PROPERTIES_MATCH)?;
+        self.drain_signals();
+        Ok(())
+    }
+
+    fn on_device_lost(&mut self) -> Result<()> {
+        self.scrubbing = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,434 @@
+extern crate alsa;
+extern crate regex;
+
+use std::io::{ self, Read, Write };
+use std::time::{ Duration, Instant };
+use anyhow::Result;
+use alsa::{ Direction, Rawmidi, Ctl };
+use alsa::poll::{ self, Descriptors, pollfd };
+use regex::Regex;
+use crate::alsa_midi::{ Event, PadControl };
+use crate::drivers;
+
+/*
+ * Reads MIDI bytes into Events. Running status is followed, SysEx is collected until F7, and
+ * real time messages may turn up anywhere, even inside other messages. Undefined status bytes
+ * are skipped.
+ */
+pub struct MidiParser {
+    status: Option<u8>,
+    data: Vec<u8>,
+    sysex: Option<Vec<u8>>
+}
+
+impl MidiParser {
+    pub fn new() -> MidiParser {
+        MidiParser {
+            status: None,
+            data: Vec::new(),
+            sysex: None
+        }
+    }
+
+    /*
+     * How many data bytes follow a status byte.
+     */
+    fn data_length(status: u8) -> usize {
+        match status {
+            0xc0..=0xdf | 0xf1 | 0xf3 => 1,
+            0x80..=0xef | 0xf2 => 2,
+            _ => 0
+        }
+    }
+
+    pub fn parse(&mut self, bytes: &[u8]) -> Vec<Event> {
+        let mut result = Vec::new();
+        for &byte in bytes {
+            match byte {
+                // Real time: clock, start, stop and so on, which don't interrupt anything.
+                0xf8..=0xff => result.extend(MidiParser::realtime(byte)),
+                0xf0 => {
+                    self.sysex = Some(vec![byte]);
+                    self.status = None;
+                },
+                0xf7 => if let Some(mut data) = self.sysex.take() {
+                    data.push(byte);
+                    result.push(Event::SysEx(data));
+                },
+                0x80..=0xf6 => {
+                    // Any other status ends an unfinished SysEx, which is dropped.
+                    self.sysex = None;
+                    self.status = Some(byte);
+                    self.data.clear();
+                    if MidiParser::data_length(byte) == 0 {
+                        self.status = None;
+                        if byte == 0xf6 {
+                            result.push(Event::TuneRequest);
+                        }
+                    }
+                },
+                _ => if let Some(sysex) = &mut self.sysex {
+                    sysex.push(byte);
+                } else if let Some(status) = self.status {
+                    self.data.push(byte);
+                    if self.data.len() == MidiParser::data_length(status) {
+                        result.extend(MidiParser::event(status, &self.data));
+                        self.data.clear();
+                        // Only channel messages have running status.
+                        if status >= 0xf0 {
+                            self.status = None;
+                        }
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn event(status: u8, data: &[u8]) -> Option<Event> {
+        let channel = status & 0x0f;
+        match status {
+            0x80..=0x8f => Some(Event::NoteOff { channel, note: data[0], velocity: data[1] }),
+            0x90..=0x9f => Some(Event::Note { channel, note: data[0], velocity: data[1] }),
+            0xa0..=0xaf => Some(Event::KeyPressure { channel, note: data[0], pressure: data[1] }),
+            0xb0..=0xbf => Some(Event::Control { channel, param: data[0] as u32, value: data[1] as i32 }),
+            0xc0..=0xcf => Some(Event::ProgramChange { channel, program: data[0] }),
+            0xd0..=0xdf => Some(Event::ChannelPressure { channel, pressure: data[0] }),
+            0xe0..=0xef => Some(Event::PitchBend {
+                channel,
+                value: ((data[1] as i16) << 7 | data[0] as i16) - 0x2000
+            }),
+            0xf1 => Some(Event::QuarterFrame(data[0])),
+            0xf2 => Some(Event::SongPosition((data[1] as u16) << 7 | data[0] as u16)),
+            0xf3 => Some(Event::SongSelect(data[0])),
+            _ => None
+        }
+    }
+
+    fn realtime(byte: u8) -> Option<Event> {
+        match byte {
+            0xf8 => Some(Event::Clock),
+            0xfa => Some(Event::Start),
+            0xfb => Some(Event::Continue),
+            0xfc => Some(Event::Stop),
+            0xfe => Some(Event::ActiveSensing),
+            0xff => Some(Event::Reset),
+            _ => None
+        }
+    }
+}
+
+/*
+ * Writes Events as MIDI bytes, leaving out the status byte when it is the same as the last one.
+ */
+pub struct MidiEncoder {
+    status: Option<u8>
+}
+
+impl MidiEncoder {
+    pub fn new() -> MidiEncoder {
+        MidiEncoder {
+            status: None
+        }
+    }
+
+    pub fn encode(&mut self, event: &Event, out: &mut Vec<u8>) {
+        let (status, data) = match event {
+            Event::Note { channel, note, velocity } => (0x90 | (channel & 0x0f), vec![*note, *velocity]),
+            Event::NoteOff { channel, note, velocity } => (0x80 | (channel & 0x0f), vec![*note, *velocity]),
+            Event::KeyPressure { channel, note, pressure } => (0xa0 | (channel & 0x0f), vec![*note, *pressure]),
+            Event::Control { channel, param, value } => (0xb0 | (channel & 0x0f), vec![*param as u8, *value as u8]),
+            Event::ProgramChange { channel, program } => (0xc0 | (channel & 0x0f), vec![*program]),
+            Event::ChannelPressure { channel, pressure } => (0xd0 | (channel & 0x0f), vec![*pressure]),
+            Event::PitchBend { channel, value } => {
+                let value = (*value as i32 + 0x2000).clamp(0, 0x3fff);
+                (0xe0 | (channel & 0x0f), vec![value as u8, (value >> 7) as u8])
+            },
+            Event::SysEx(data) => {
+                out.extend(data);
+                self.status = None;
+                return;
+            },
+            Event::QuarterFrame(value) => (0xf1, vec![*value]),
+            Event::SongPosition(position) => (0xf2, vec![*position as u8, (*position >> 7) as u8]),
+            Event::SongSelect(song) => (0xf3, vec![*song]),
+            Event::TuneRequest => (0xf6, vec![]),
+            // Real time messages leave running status alone.
+            Event::Clock => return out.push(0xf8),
+            Event::Start => return out.push(0xfa),
+            Event::Continue => return out.push(0xfb),
+            Event::Stop => return out.push(0xfc),
+            Event::ActiveSensing => return out.push(0xfe),
+            Event::Reset => return out.push(0xff)
+        };
+        // Only channel messages have running status.
+        if self.status != Some(status) || status >= 0xf0 {
+            out.push(status);
+        }
+        self.status = if status < 0xf0 { Some(status) } else { None };
+        out.extend(data.iter().map(|d| d & 0x7f));
+    }
+}
+
+/*
+ * Talks to the pad through its ALSA rawmidi device, e.g. hw:1,0, rather than the sequencer. That
+ * gives us the pad to ourselves. The device is either given, or the first one on a card whose
+ * name matches. If it goes away it is looked for again every second.
+ */
+pub struct RawMidiPad {
+    device: Option<String>,
+    card: Regex,
+    // Input and output, while the device is open.
+    ports: Option<(Rawmidi, Rawmidi)>,
+    parser: MidiParser,
+    encoder: MidiEncoder,
+    retry_at: Instant
+}
+
+const RETRY: Duration = Duration::from_secs(1);
+
+/*
+ * ALSA reports errors as negative errno values, which io::Error doesn't recognise.
+ */
+fn os_error(e: io::Error) -> io::Error {
+    match e.raw_os_error() {
+        Some(code) if code < 0 => io::Error::from_raw_os_error(-code),
+        _ => e
+    }
+}
+
+impl RawMidiPad {
+    pub fn new(device: Option<String>, card: Regex) -> RawMidiPad {
+        RawMidiPad {
+            device,
+            card,
+            ports: None,
+            parser: MidiParser::new(),
+            encoder: MidiEncoder::new(),
+            retry_at: Instant::now()
+        }
+    }
+
+    /*
+     * The first rawmidi device on a card whose name matches.
+     */
+    fn find_device(&self) -> Result<Option<String>> {
+        for card in alsa::card::Iter::new() {
+            let card = card?;
+            if !self.card.is_match(&card.get_name()?) {
+                continue;
+            }
+            let ctl = Ctl::from_card(&card, false)?;
+            if let Some(info) = alsa::rawmidi::Iter::new(&ctl).next() {
+                return Ok(Some(format!("hw:{},{}", card.get_index(), info?.get_device())));
+            }
+        }
+        Ok(None)
+    }
+
+    /*
+     * Opens the device if it is there, and asks what it is. Returns whether it was opened.
+     */
+    fn open(&mut self) -> Result<bool> {
+        let device = match &self.device {
+            Some(device) => device.clone(),
+            None => match self.find_device()? {
+                Some(device) => device,
+                None => return Ok(false)
+            }
+        };
+        let ports = Rawmidi::new(&device, Direction::Capture, true)
+            .and_then(|input| Ok((input, Rawmidi::new(&device, Direction::Playback, false)?)));
+        match ports {
+            Ok(ports) => {
+                eprintln!("Opened {}", device);
+                self.ports = Some(ports);
+                self.parser = MidiParser::new();
+                self.encoder = MidiEncoder::new();
+                self.process_in(vec![Event::SysEx(drivers::DEVICE_INQUIRY.to_vec())])?;
+                Ok(true)
+            },
+            // Not plugged in yet.
+            Err(_) => Ok(false)
+        }
+    }
+
+    fn close(&mut self, e: io::Error) {
+        if let Some((input, _)) = self.ports.take() {
+            eprintln!("Lost {}: {}", input.name().unwrap_or_default(), e);
+        }
+        self.retry_at = Instant::now() + RETRY;
+    }
+
+    /*
+     * Opens the pad, waiting a moment for it to answer. Returns its answer to the inquiry, or
+     * None if it didn't answer, in which case it is assumed to be a Mini Mk1.
+     */
+    pub fn connect(&mut self) -> Result<Option<Vec<u8>>> {
+        if !self.open()? {
+            eprintln!("No rawmidi device found; waiting for one");
+            return Ok(None);
+        }
+        let deadline = Instant::now() + drivers::INQUIRY_TIMEOUT;
+        while Instant::now() < deadline {
+            let mut fds = self.poll_descriptors()?;
+            poll::poll(&mut fds, 100)?;
+            for ev in self.process_out()? {
+                if let Event::SysEx(data) = ev {
+                    if drivers::driver_for(&data).is_some() {
+                        return Ok(Some(data));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl PadControl for RawMidiPad {
+
+    fn process_in(&mut self, events: Vec<Event>) -> Result<()> {
+        let mut bytes = Vec::new();
+        for event in &events {
+            self.encoder.encode(event, &mut bytes);
+        }
+        let written = match &self.ports {
+            Some((_, output)) => output.io().write_all(&bytes).map_err(os_error),
+            None => return Ok(())
+        };
+        if let Err(e) = written {
+            self.close(e);
+        }
+        Ok(())
+    }
+
+    fn process_out(&mut self) -> Result<Vec<Event>> {
+        if self.ports.is_none() {
+            if Instant::now() >= self.retry_at {
+                self.retry_at = Instant::now() + RETRY;
+                self.open()?;
+            }
+            return Ok(Vec::new());
+        }
+        let mut result = Vec::new();
+        let mut buffer = [0u8; 256];
+        while let Some((input, _)) = &self.ports {
+            match input.io().read(&mut buffer).map_err(os_error) {
+                Ok(0) => break,
+                Ok(n) => result.append(&mut self.parser.parse(&buffer[..n])),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => self.close(e)
+            }
+        }
+        Ok(result)
+    }
+
+    fn poll_descriptors(&self) -> Result<Vec<pollfd>> {
+        match &self.ports {
+            Some((input, _)) => Ok(input.get()?),
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn connected(&self) -> bool {
+        self.ports.is_some()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(channel: u8, note: u8, velocity: u8) -> Event {
+        Event::Note { channel, note, velocity }
+    }
+
+    #[test]
+    fn running_status_carries_across_messages_and_reads() {
+        let mut parser = MidiParser::new();
+        assert_eq!(parser.parse(&[0x92, 0x10, 0x7f, 0x11]), vec![note(2, 0x10, 0x7f)]);
+        assert_eq!(parser.parse(&[0x00, 0x12, 0x40]), vec![note(2, 0x11, 0), note(2, 0x12, 0x40)]);
+        // System common messages cancel it.
+        assert_eq!(parser.parse(&[0xf3, 0x05, 0x10, 0x7f]), vec![Event::SongSelect(5)]);
+        assert_eq!(parser.parse(&[0xb0, 0x00, 0x01, 0x68, 0x7f]), vec![
+            Event::Control { channel: 0, param: 0, value: 1 },
+            Event::Control { channel: 0, param: 0x68, value: 0x7f }
+        ]);
+    }
+
+    #[test]
+    fn realtime_bytes_inside_messages() {
+        let mut parser = MidiParser::new();
+        assert_eq!(parser.parse(&[0x90, 0xf8, 0x10, 0xfe, 0x7f]),
+                   vec![Event::Clock, Event::ActiveSensing, note(0, 0x10, 0x7f)]);
+        assert_eq!(parser.parse(&[0xf0, 0x00, 0xfa, 0x20, 0xf7]),
+                   vec![Event::Start, Event::SysEx(vec![0xf0, 0x00, 0x20, 0xf7])]);
+        // Undefined ones are dropped without disturbing anything.
+        assert_eq!(parser.parse(&[0x90, 0x11, 0xf9, 0xfd, 0x22]), vec![note(0, 0x11, 0x22)]);
+    }
+
+    #[test]
+    fn sysex_split_across_reads() {
+        let mut parser = MidiParser::new();
+        assert_eq!(parser.parse(&[0xf0, 0x7e, 0x7f]), vec![]);
+        assert_eq!(parser.parse(&[0x06, 0x02]), vec![]);
+        assert_eq!(parser.parse(&[0x00, 0xf7, 0x90, 0x01, 0x02]), vec![
+            Event::SysEx(vec![0xf0, 0x7e, 0x7f, 0x06, 0x02, 0x00, 0xf7]),
+            note(0, 0x01, 0x02)
+        ]);
+        // One cut short by another status byte is dropped, as is a stray end.
+        assert_eq!(parser.parse(&[0xf0, 0x01, 0x02, 0x80, 0x03, 0x04, 0xf7]), vec![
+            Event::NoteOff { channel: 0, note: 3, velocity: 4 }
+        ]);
+    }
+
+    #[test]
+    fn encoder_uses_running_status() {
+        let mut encoder = MidiEncoder::new();
+        let mut out = Vec::new();
+        for event in [note(0, 1, 2), note(0, 3, 4), Event::Clock, note(0, 5, 6), note(1, 7, 8),
+                      Event::TuneRequest, note(1, 9, 10)] {
+            encoder.encode(&event, &mut out);
+        }
+        assert_eq!(out, vec![0x90, 1, 2, 3, 4, 0xf8, 5, 6, 0x91, 7, 8, 0xf6, 0x91, 9, 10]);
+    }
+
+    #[test]
+    fn encoded_events_parse_back() {
+        let events = vec![
+            note(0, 0x10, 0x7f),
+            note(0, 0x11, 0),
+            Event::NoteOff { channel: 15, note: 0x7f, velocity: 0x40 },
+            Event::KeyPressure { channel: 3, note: 0x20, pressure: 0x30 },
+            Event::Control { channel: 0, param: 0x68, value: 0x7f },
+            Event::Control { channel: 0, param: 0x69, value: 0 },
+            Event::Clock,
+            Event::ProgramChange { channel: 9, program: 0x7f },
+            Event::ProgramChange { channel: 9, program: 0 },
+            Event::ChannelPressure { channel: 4, pressure: 0x55 },
+            Event::PitchBend { channel: 2, value: -0x2000 },
+            Event::PitchBend { channel: 2, value: 0x1fff },
+            Event::SysEx(vec![0xf0, 0x00, 0x20, 0x29, 0x02, 0x18, 0x0e, 0x00, 0xf7]),
+            Event::QuarterFrame(0x35),
+            Event::SongPosition(0x3fff),
+            Event::SongSelect(0x12),
+            Event::TuneRequest,
+            Event::Start,
+            Event::Continue,
+            Event::Stop,
+            Event::ActiveSensing,
+            Event::Reset
+        ];
+        let mut encoder = MidiEncoder::new();
+        let mut out = Vec::new();
+        for event in &events {
+            encoder.encode(event, &mut out);
+        }
+        assert_eq!(MidiParser::new().parse(&out), events);
+        // However the bytes are split up.
+        let mut parser = MidiParser::new();
+        let parsed: Vec<Event> = out.chunks(3).flat_map(|chunk| parser.parse(chunk)).collect();
+        assert_eq!(parsed, events);
+    }
+}
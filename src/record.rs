@@ -0,0 +1,456 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::fs::{ self, File };
+use std::io::{ BufWriter, Write };
+use std::path::Path;
+use anyhow::{ Result, Error, Context };
+use alsa::poll::pollfd;
+use crate::alsa_midi::{ Event, PadControl };
+use crate::blinken::BlinkenPad;
+use crate::config::Config;
+use crate::drivers::{ self, Mk1, PadDriver };
+use crate::launchpad::{ LaunchPad, PadArea, PadColour, PadLocation };
+use crate::virtual_pad::VirtualPad;
+
+/*
+ * Writes down everything BlinkenPad::process_all sees and sends, one step at a time:
+ *
+ *   step <tick> [tick]
+ *   in <location> down|up
+ *   out <location> #rrggbb [flash]
+ *
+ * "tick" is there when the step advanced the tick count.
+ */
+pub struct Recorder {
+    out: BufWriter<File>
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Recorder> {
+        let file = File::create(path).with_context(|| format!("Creating {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        writeln!(out, "# blinkenpad recording")?;
+        Ok(Recorder { out })
+    }
+
+    pub fn record(&mut self, tick: u32, ticked: bool, input: &[(PadLocation, bool)],
+                  output: &[(PadLocation, PadColour)]) -> Result<()> {
+        writeln!(self.out, "step {}{}", tick, if ticked { " tick" } else { "" })?;
+        for (loc, pressed) in input {
+            writeln!(self.out, "in {} {}", loc, if *pressed { "down" } else { "up" })?;
+        }
+        for (loc, colour) in output {
+            writeln!(self.out, "out {} {}", loc, colour)?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+struct Step {
+    tick: u32,
+    ticked: bool,
+    input: Vec<(PadLocation, bool)>,
+    output: Vec<(PadLocation, PadColour)>
+}
+
+fn parse_number(word: Option<&str>) -> Result<u8> {
+    let word = word.ok_or(Error::msg("Line ends early"))?;
+    Ok(word.parse()?)
+}
+
+fn parse_location<'a, I: Iterator<Item = &'a str>>(words: &mut I) -> Result<PadLocation> {
+    let loc = match words.next() {
+        Some("pad") => PadLocation::OnPad(parse_number(words.next())?, parse_number(words.next())?),
+        Some("letter") => PadLocation::Letters(parse_number(words.next())?),
+        Some("number") => PadLocation::Numbers(parse_number(words.next())?),
+        other => return Err(Error::msg(format!("Unknown location {:?}", other)))
+    };
+    match loc {
+        PadLocation::OnPad(x, y) if x < 8 && y < 8 => Ok(loc),
+        PadLocation::Letters(n) | PadLocation::Numbers(n) if n < 8 => Ok(loc),
+        _ => Err(Error::msg(format!("Location {} is off the pad", loc)))
+    }
+}
+
+fn parse_colour<'a, I: Iterator<Item = &'a str>>(words: &mut I) -> Result<PadColour> {
+    let word = words.next().ok_or(Error::msg("Line ends early"))?;
+    let colour = PadColour::parse(word).ok_or(Error::msg(format!("Unknown colour {}", word)))?;
+    match words.next() {
+        Some("flash") => Ok(colour.flashing()),
+        None => Ok(colour),
+        Some(other) => Err(Error::msg(format!("Unexpected {}", other)))
+    }
+}
+
+fn load_steps(path: &Path) -> Result<Vec<Step>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+    let mut steps: Vec<Step> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let result: Result<()> = (|| {
+            match words.next() {
+                Some("step") => steps.push(Step {
+                    tick: words.next().ok_or(Error::msg("Step without tick"))?.parse()?,
+                    ticked: words.next() == Some("tick"),
+                    input: Vec::new(),
+                    output: Vec::new()
+                }),
+                Some("in") => {
+                    let loc = parse_location(&mut words)?;
+                    let pressed = match words.next() {
+                        Some("down") => true,
+                        Some("up") => false,
+                        _ => return Err(Error::msg("Expected down or up"))
+                    };
+                    steps.last_mut().ok_or(Error::msg("Input before first step"))?
+                        .input.push((loc, pressed));
+                },
+                Some("out") => {
+                    let loc = parse_location(&mut words)?;
+                    let colour = parse_colour(&mut words)?;
+                    steps.last_mut().ok_or(Error::msg("Output before first step"))?
+                        .output.push((loc, colour));
+                },
+                _ => return Err(Error::msg("Unknown line"))
+            }
+            Ok(())
+        })();
+        result.with_context(|| format!("{}:{}", path.display(), n + 1))?;
+    }
+    Ok(steps)
+}
+
+/*
+ * The models a replay is run against.
+ */
+#[derive(Clone, Copy, Debug)]
+enum Model {
+    Mk1,
+    Mk2,
+    MiniMk3,
+    X
+}
+
+const MODELS: [Model; 4] = [Model::Mk1, Model::Mk2, Model::MiniMk3, Model::X];
+
+/*
+ * What an LED shows, in the terms of the model it is on.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Led {
+    // The Mini Mk1's red and green bits, as in a velocity, and whether it flashes.
+    Levels(u8, bool),
+    // RGB as the model takes it: 6 bits on the Mk2, 7 on the others.
+    Rgb(u8, u8, u8),
+    // A palette colour, flashing.
+    Flashing(u8)
+}
+
+impl Model {
+    /*
+     * The Device Inquiry reply the model sends, which is how LaunchPad picks its driver.
+     */
+    fn inquiry_reply(self) -> Vec<u8> {
+        let family = match self {
+            Model::Mk1 => [0x36, 0x00],
+            Model::Mk2 => [0x69, 0x00],
+            Model::MiniMk3 => [0x13, 0x01],
+            Model::X => [0x03, 0x01]
+        };
+        let mut reply = vec![0xf0, 0x7e, 0x00, 0x06, 0x02, 0x00, 0x20, 0x29];
+        reply.extend(family);
+        reply.extend([0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf7]);
+        reply
+    }
+
+    // The device id in the model's own SysEx.
+    fn device(self) -> Option<u8> {
+        match self {
+            Model::Mk1 => None,
+            Model::Mk2 => Some(0x18),
+            Model::MiniMk3 => Some(0x0d),
+            Model::X => Some(0x0c)
+        }
+    }
+
+    fn led(self, colour: &PadColour) -> Led {
+        let (r, g, b) = colour.components();
+        match self {
+            Model::Mk1 => {
+                let velocity = colour.mk1_velocity();
+                Led::Levels(velocity, colour.is_flashing() && velocity != 0)
+            },
+            _ if colour.is_flashing() => Led::Flashing(colour.palette_index()),
+            Model::Mk2 => Led::Rgb(r >> 2, g >> 2, b >> 2),
+            Model::MiniMk3 | Model::X => Led::Rgb(r >> 1, g >> 1, b >> 1)
+        }
+    }
+}
+
+// LEDs are kept in the virtual pad's order: the grid by rows, the Letters, the Numbers.
+fn index(loc: &PadLocation) -> usize {
+    match loc {
+        PadLocation::OnPad(x, y) => (x + y * 8) as usize,
+        PadLocation::Letters(l) => 64 + *l as usize,
+        PadLocation::Numbers(n) => 72 + *n as usize
+    }
+}
+
+fn location(index: usize) -> PadLocation {
+    match index {
+        0..=63 => PadLocation::on_pad((index % 8) as u8, (index / 8) as u8),
+        64..=71 => PadLocation::letter((index - 64) as u8),
+        _ => PadLocation::number((index - 72) as u8)
+    }
+}
+
+/*
+ * Stands in for the sequencer during a replay, as a Launchpad of the given model: it answers
+ * the Device Inquiry, hands over the recorded input a step at a time as that model would send
+ * it, and works out from the MIDI sent back what each LED shows. The Mini Mk1 is a headless
+ * virtual pad, which double buffers, flashes and takes rapid updates like the real one.
+ */
+struct MockPad {
+    model: Model,
+    driver: Box<dyn PadDriver>,
+    reply: Option<Vec<u8>>,
+    input: VecDeque<Vec<Event>>,
+    mk1: Option<VirtualPad>,
+    leds: Rc<RefCell<Vec<Led>>>
+}
+
+impl MockPad {
+    fn new(model: Model, input: &[Vec<(PadLocation, bool)>]) -> Result<MockPad> {
+        let reply = model.inquiry_reply();
+        let driver = drivers::driver_for(&reply)
+            .ok_or(Error::msg(format!("No driver for {:?}", model)))?;
+        let input = input.iter()
+            .map(|step| step.iter().map(|(loc, pressed)| MockPad::button(&*driver, loc, *pressed)).collect())
+            .collect::<Result<_>>()?;
+        Ok(MockPad {
+            model,
+            driver,
+            reply: Some(reply),
+            input,
+            mk1: match model {
+                Model::Mk1 => Some(VirtualPad::new(false)?),
+                _ => None
+            },
+            leds: Rc::new(RefCell::new(vec![model.led(&PadColour::new(0, 0)); 80]))
+        })
+    }
+
+    /*
+     * The event a button sends, found by asking the driver so that it holds for every model.
+     */
+    fn button(driver: &dyn PadDriver, loc: &PadLocation, pressed: bool) -> Result<Event> {
+        let value = if pressed { 127 } else { 0 };
+        (0..128u8)
+            .flat_map(|n| [Event::Note { channel: 0, note: n, velocity: value },
+                           Event::Control { channel: 0, param: n as u32, value: value as i32 }])
+            .find(|event| driver.decode(event) == Some((loc.clone(), pressed)))
+            .ok_or(Error::msg(format!("The {} has no button at {}", driver.name(), loc)))
+    }
+
+    // LEDs are numbered as the notes and controllers their buttons send.
+    fn led_index(&self, led: u8) -> Result<usize> {
+        [Event::Note { channel: 0, note: led, velocity: 127 },
+         Event::Control { channel: 0, param: led as u32, value: 127 }].iter()
+            .find_map(|event| self.driver.decode(event))
+            .map(|(loc, _)| index(&loc))
+            .ok_or(Error::msg(format!("The {} has no LED {}", self.driver.name(), led)))
+    }
+
+    fn sysex(&mut self, data: &[u8]) -> Result<()> {
+        let (command, body) = match data {
+            [0xf0, 0x00, 0x20, 0x29, 0x02, device, command, body @ .., 0xf7]
+                if Some(*device) == self.model.device() => (*command, body),
+            _ => return Err(Error::msg(format!("Unexpected SysEx {:02x?}", data)))
+        };
+        let mut leds = Vec::new();
+        match (self.model, command) {
+            (Model::Mk2, 0x0b) if body.len() % 4 == 0 => for led in body.chunks(4) {
+                leds.push((led[0], Led::Rgb(led[1], led[2], led[3])));
+            },
+            (Model::Mk2, 0x23) if body.len() % 2 == 0 => for led in body.chunks(2) {
+                leds.push((led[0], Led::Flashing(led[1])));
+            },
+            (Model::MiniMk3 | Model::X, 0x03) => {
+                let mut rest = body;
+                while !rest.is_empty() {
+                    rest = match rest {
+                        [0x01, led, _, colour, rest @ ..] => {
+                            leds.push((*led, Led::Flashing(*colour)));
+                            rest
+                        },
+                        [0x03, led, r, g, b, rest @ ..] => {
+                            leds.push((*led, Led::Rgb(*r, *g, *b)));
+                            rest
+                        },
+                        _ => return Err(Error::msg(format!("Bad lighting spec in {:02x?}", data)))
+                    };
+                }
+            },
+            // The layout, programmer mode and brightness light nothing.
+            (Model::Mk2, 0x22) | (Model::MiniMk3 | Model::X, 0x0e | 0x08) => {},
+            _ => return Err(Error::msg(format!("Unexpected SysEx {:02x?}", data)))
+        }
+        for (led, shown) in leds {
+            let index = self.led_index(led)?;
+            self.leds.borrow_mut()[index] = shown;
+        }
+        Ok(())
+    }
+}
+
+impl PadControl for MockPad {
+    fn process_out(&mut self) -> Result<Vec<Event>> {
+        if let Some(reply) = self.reply.take() {
+            return Ok(vec![Event::SysEx(reply)]);
+        }
+        Ok(self.input.pop_front().unwrap_or_default())
+    }
+
+    fn process_in(&mut self, events: Vec<Event>) -> Result<()> {
+        if let Some(mk1) = &mut self.mk1 {
+            mk1.process_in(events)?;
+            let mut leds = self.leds.borrow_mut();
+            for (index, led) in leds.iter_mut().enumerate() {
+                let (velocity, flashing) = mk1.led(index);
+                *led = Led::Levels(velocity, flashing);
+            }
+            return Ok(());
+        }
+        for event in events {
+            match event {
+                Event::SysEx(data) => self.sysex(&data)?,
+                other => return Err(Error::msg(format!("The {} was sent {:?}", self.driver.name(), other)))
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_descriptors(&self) -> Result<Vec<pollfd>> {
+        Ok(Vec::new())
+    }
+}
+
+/*
+ * Plays the steps back through the layout on one model, and reports every step after which an
+ * LED shows something other than what was recorded. Returns the number of steps that differed.
+ */
+fn replay_as(steps: &[Step], config: &Config, model: Model) -> Result<usize> {
+    let input: Vec<Vec<(PadLocation, bool)>> = steps.iter().map(|s| s.input.clone()).collect();
+    let mut mock = MockPad::new(model, &input)?;
+    let leds = mock.leds.clone();
+    let name = mock.driver.name();
+    let mut pad = LaunchPad::new(&mut mock, Box::new(Mk1::new()));
+    // Takes the Device Inquiry reply, so that the right driver is used from the start.
+    pad.process_out()?;
+    let mut blinken = BlinkenPad::new(&mut pad);
+    blinken.load(config)?;
+    blinken.clear_pad()?;
+
+    let mut expected = vec![model.led(&PadColour::new(0, 0)); 80];
+    let mut mismatches = 0;
+    for (n, step) in steps.iter().enumerate() {
+        blinken.process_all(step.ticked)?;
+        if blinken.ticks() != step.tick {
+            return Err(Error::msg(format!("Step {} is at tick {} but the replay is at {}",
+                                          n + 1, step.tick, blinken.ticks())));
+        }
+        for (loc, colour) in &step.output {
+            expected[index(loc)] = model.led(colour);
+        }
+        let shown = leds.borrow();
+        let wrong: Vec<usize> = (0..80).filter(|i| shown[*i] != expected[*i]).collect();
+        if !wrong.is_empty() {
+            mismatches += 1;
+            println!("Step {} (tick {}) differs on the {}", n + 1, step.tick, name);
+            for i in wrong {
+                println!("  {} shows {:?} instead of {:?}", location(i), shown[i], expected[i]);
+            }
+            // Carry on from what is shown, so that later steps only report what they change.
+            expected.clone_from(&shown);
+        }
+    }
+    println!("Replayed {} steps on the {}, {} differed", steps.len(), name, mismatches);
+    Ok(mismatches)
+}
+
+/*
+ * Plays a recording back through the given layout on every model Blinkenpad drives, and
+ * reports every step where the lights differ. Returns the number of steps that differed.
+ */
+pub fn replay(path: &Path, config: &Config) -> Result<usize> {
+    let steps = load_steps(path)?;
+    let mut mismatches = 0;
+    for model in MODELS {
+        mismatches += replay_as(&steps, config, model)?;
+    }
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+    }
+
+    #[test]
+    fn recording_replays_on_every_model() {
+        let config = Config::load(&testdata("replay.toml")).unwrap();
+        let steps = load_steps(&testdata("replay.txt")).unwrap();
+        for model in MODELS {
+            assert_eq!(replay_as(&steps, &config, model).unwrap(), 0, "{:?}", model);
+        }
+    }
+
+    #[test]
+    fn changed_recording_differs() {
+        let config = Config::load(&testdata("replay.toml")).unwrap();
+        let mut steps = load_steps(&testdata("replay.txt")).unwrap();
+        let step = steps.iter_mut().rev().find(|s| !s.output.is_empty()).unwrap();
+        step.output[0].1 = PadColour::rgb(0, 0, 255);
+        for model in MODELS {
+            assert!(replay_as(&steps, &config, model).unwrap() > 0, "{:?}", model);
+        }
+    }
+
+    #[test]
+    fn flashing_shows_on_every_model() {
+        let frames: Vec<Vec<(PadLocation, PadColour)>> = vec![
+            // Enough LEDs for the Mini Mk1 to get a rapid update.
+            (0..64).map(|i| (location(i), PadColour::new(i as u8 % 4, i as u8 / 16))).collect(),
+            vec![(PadLocation::on_pad(1, 1), PadColour::new(3, 0).flashing()),
+                 (PadLocation::letter(2), PadColour::new(3, 3).flashing()),
+                 (PadLocation::number(3), PadColour::rgb(0, 0, 255))],
+            vec![(PadLocation::on_pad(2, 2), PadColour::new(2, 3))],
+            vec![(PadLocation::on_pad(1, 1), PadColour::new(3, 0)),
+                 (PadLocation::letter(2), PadColour::new(0, 0))]
+        ];
+        for model in MODELS {
+            let mut mock = MockPad::new(model, &[]).unwrap();
+            let leds = mock.leds.clone();
+            let mut pad = LaunchPad::new(&mut mock, Box::new(Mk1::new()));
+            pad.process_out().unwrap();
+            let mut expected = vec![model.led(&PadColour::new(0, 0)); 80];
+            for frame in &frames {
+                for (loc, colour) in frame {
+                    expected[index(loc)] = model.led(colour);
+                }
+                pad.process_in(frame.clone()).unwrap();
+                assert_eq!(*leds.borrow(), expected, "{:?}", model);
+            }
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use crate::blinken::PluginArea;
+use crate::events::PadEvent;
+use crate::launchpad::{PadLocation, PadColour};
+use anyhow::Result;
+
+/*
+ * An area kept for clients of the control socket, which light its cells by the plugin's name.
+ * Nothing is lit until they do, and what they lit is forgotten when the layout is reloaded.
+ * Presses reach them through the socket's event stream rather than through the plugin.
+ */
+pub struct RemotePlugin {
+    cells: Vec<(PadLocation, PadColour)>
+}
+
+impl RemotePlugin {
+    pub fn new() -> RemotePlugin {
+        RemotePlugin {
+            cells: Vec::new()
+        }
+    }
+}
+
+impl PluginArea for RemotePlugin {
+    fn process_input(&mut self, _tick: u32, _events: &Vec<PadEvent>) -> Result<()> {
+        Ok(())
+    }
+
+    fn process_output(&mut self, _tick: u32) -> Result<Vec<(PadLocation, PadColour)>> {
+        Ok(self.cells.clone())
+    }
+
+    fn set_cells(&mut self, cells: &[(PadLocation, Option<PadColour>)]) -> Result<()> {
+        for (loc, colour) in cells {
+            self.cells.retain(|(l, _)| l != loc);
+            if let Some(colour) = colour {
+                self.cells.push((loc.clone(), *colour));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::process::{ Command, Stdio };
+use std::sync::mpsc::{ self, Receiver, Sender };
+use std::thread;
+use std::time::{ Duration, Instant };
+use crate::blinken::{ PluginArea, grid_only };
+use crate::events::PadEvent;
+use crate::launchpad::{PadLocation, PadColour};
+use anyhow::{ Result, Error };
+
+/*
+ * A command run now and then to find out what colour a cell should be. The first line it
+ * prints picks from values, and otherwise the cell is lit in its colour if the command
+ * succeeded, and in off if it failed.
+ */
+pub struct ShellStatus {
+    pub command: String,
+    // In ticks, from when the last run finished.
+    pub interval: u32,
+    pub off: PadColour,
+    pub values: HashMap<String, PadColour>
+}
+
+/*
+ * A cell that runs a command line when pressed.
+ */
+pub struct ShellCell {
+    pub x: u8,
+    pub y: u8,
+    pub command: String,
+    pub colour: PadColour,
+    pub status: Option<ShellStatus>
+}
+
+// What a command that finished was run for, by index into cells.
+enum Run {
+    Press(usize),
+    Status(usize)
+}
+
+// How a status command went: whether it succeeded, and the first line it printed.
+type Outcome = (bool, String);
+
+// Commands still running after this are killed, along with anything they started.
+const TIME_LIMIT: Duration = Duration::from_secs(30);
+const WAIT_STEP: Duration = Duration::from_millis(50);
+
+/*
+ * Runs a command line until it has exited and closed its output, or until TIME_LIMIT. It gets a
+ * process group of its own so that a hung pipeline is killed as a whole, but anything it leaves
+ * running in the background when it finishes in time is left alone.
+ */
+fn run_command(command: &str, capture: bool) -> Result<Outcome> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(if capture { Stdio::piped() } else { Stdio::null() })
+        .process_group(0)
+        .spawn()
+        .map_err(|e| Error::msg(format!("Running {}: {}", command, e)))?;
+    // Read alongside, so that a command that prints a lot isn't stuck on a full pipe.
+    let (sender, printed) = mpsc::channel();
+    let mut text = match child.stdout.take() {
+        Some(mut stdout) => {
+            thread::spawn(move || {
+                let mut text = Vec::new();
+                let _ = stdout.read_to_end(&mut text);
+                let _ = sender.send(text);
+            });
+            None
+        },
+        None => Some(Vec::new())
+    };
+    let started = Instant::now();
+    let mut status = None;
+    while status.is_none() || text.is_none() {
+        if started.elapsed() >= TIME_LIMIT {
+            unsafe { libc::kill(-(child.id() as i32), libc::SIGKILL) };
+            let _ = child.wait();
+            return Err(Error::msg(format!("Killed {} after {} seconds", command, TIME_LIMIT.as_secs())));
+        }
+        if status.is_none() {
+            status = child.try_wait()?;
+        }
+        if text.is_none() {
+            text = printed.recv_timeout(WAIT_STEP).ok();
+        } else {
+            thread::sleep(WAIT_STEP);
+        }
+    }
+    let (status, text) = (status.unwrap_or_default(), text.unwrap_or_default());
+    let text = String::from_utf8_lossy(&text);
+    Ok((status.success(), text.lines().next().unwrap_or("").trim().to_string()))
+}
+
+/*
+ * Cells that run commands through sh, each in a thread of its own so that the pad never waits
+ * on them. Commands get no input, and what pressed commands print is thrown away.
+ */
+pub struct ShellPlugin {
+    cells: Vec<ShellCell>,
+    sender: Sender<(Run, Result<Outcome>)>,
+    receiver: Receiver<(Run, Result<Outcome>)>,
+    // The cells whose pressed command is still running.
+    running: Vec<usize>,
+    // The cells whose status command is running, and those where it started before a pressed
+    // command finished, so will have to run again.
+    checking: Vec<usize>,
+    stale: Vec<usize>,
+    // The last outcome of each cell's status command, and the tick it came in on.
+    outcomes: Vec<Option<(Outcome, u32)>>
+}
+
+impl ShellPlugin {
+    pub fn new(cells: Vec<ShellCell>) -> ShellPlugin {
+        let (sender, receiver) = mpsc::channel();
+        ShellPlugin {
+            outcomes: cells.iter().map(|_| None).collect(),
+            cells,
+            sender,
+            receiver,
+            running: Vec::new(),
+            checking: Vec::new(),
+            stale: Vec::new()
+        }
+    }
+
+    fn spawn(&self, run: Run, command: &str, capture: bool) {
+        let sender = self.sender.clone();
+        let command = command.to_string();
+        thread::spawn(move || {
+            let output = run_command(&command, capture);
+            // Nobody is left to tell once the plugin has been dropped.
+            let _ = sender.send((run, output));
+        });
+    }
+
+    fn check(&mut self, i: usize) {
+        if let Some(status) = &self.cells[i].status {
+            if self.checking.contains(&i) {
+                self.stale.push(i);
+            } else {
+                self.spawn(Run::Status(i), &status.command, true);
+                self.checking.push(i);
+            }
+        }
+    }
+
+    fn cell_colour(&self, i: usize) -> PadColour {
+        let cell = &self.cells[i];
+        let colour = match (&cell.status, &self.outcomes[i]) {
+            (None, _) => cell.colour,
+            (Some(status), None) => status.off,
+            (Some(status), Some(((success, text), _))) => match status.values.get(text) {
+                Some(colour) => *colour,
+                None => if *success { cell.colour } else { status.off }
+            }
+        };
+        // Flashes while its command runs.
+        if self.running.contains(&i) { colour.flashing() } else { colour }
+    }
+}
+
+impl PluginArea for ShellPlugin {
+    fn process_input(&mut self, _tick: u32, events: &Vec<PadEvent>) -> Result<()> {
+        for event in events {
+            match event {
+                PadEvent::Press(PadLocation::OnPad(x, y)) => {
+                    // Pressing again while the command still runs does nothing.
+                    let found = self.cells.iter().position(|c| c.x == *x && c.y == *y);
+                    if let Some(i) = found.filter(|i| !self.running.contains(i)) {
+                        self.spawn(Run::Press(i), &self.cells[i].command, false);
+                        self.running.push(i);
+                    }
+                },
+                other => grid_only(other)?
+            }
+        }
+        Ok(())
+    }
+
+    fn process_output(&mut self, tick: u32) -> Result<Vec<(PadLocation, PadColour)>> {
+        while let Ok((run, outcome)) = self.receiver.try_recv() {
+            match run {
+                Run::Press(i) => {
+                    self.running.retain(|r| *r != i);
+                    match outcome {
+                        Ok((true, _)) => {},
+                        Ok((false, _)) => eprintln!("Command {} failed", self.cells[i].command),
+                        Err(e) => eprintln!("{:#}", e)
+                    }
+                    // What it did should show straight away rather than at the next check.
+                    self.check(i);
+                },
+                Run::Status(i) => {
+                    self.checking.retain(|c| *c != i);
+                    self.outcomes[i] = match outcome {
+                        Ok(outcome) => Some((outcome, tick)),
+                        Err(e) => {
+                            eprintln!("{:#}", e);
+                            Some(((false, String::new()), tick))
+                        }
+                    };
+                    if self.stale.contains(&i) {
+                        self.stale.retain(|c| *c != i);
+                        self.check(i);
+                    }
+                }
+            }
+        }
+        for i in 0..self.cells.len() {
+            let due = match (&self.cells[i].status, &self.outcomes[i]) {
+                (Some(status), Some((_, checked))) => tick >= checked + status.interval,
+                (Some(_), None) => true,
+                (None, _) => false
+            };
+            if due && !self.checking.contains(&i) {
+                self.check(i);
+            }
+        }
+        Ok((0..self.cells.len())
+            .map(|i| (PadLocation::on_pad(self.cells[i].x, self.cells[i].y), self.cell_colour(i)))
+            .collect())
+    }
+}
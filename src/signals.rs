@@ -0,0 +1,71 @@
+extern crate signal_hook;
+
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use signal_hook::consts::{ SIGINT, SIGTERM, SIGHUP };
+use signal_hook::{ flag, low_level::pipe };
+use alsa::poll::{ pollfd, Flags };
+use anyhow::Result;
+
+pub enum SignalAction {
+    Quit,
+    Reload
+}
+
+/*
+ * Catches SIGINT, SIGTERM and SIGHUP. Each wakes the main loop through a pipe, and is then
+ * picked up with pending().
+ */
+pub struct Signals {
+    wakeup: UnixStream,
+    quit: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>
+}
+
+impl Signals {
+    pub fn new() -> Result<Signals> {
+        let (wakeup, write) = UnixStream::pair()?;
+        wakeup.set_nonblocking(true)?;
+        write.set_nonblocking(true)?;
+
+        let quit = Arc::new(AtomicBool::new(false));
+        let reload = Arc::new(AtomicBool::new(false));
+        for sig in [SIGINT, SIGTERM] {
+            flag::register(sig, quit.clone())?;
+            pipe::register(sig, write.try_clone()?)?;
+        }
+        flag::register(SIGHUP, reload.clone())?;
+        pipe::register(SIGHUP, write)?;
+
+        Ok(Signals {
+            wakeup,
+            quit,
+            reload
+        })
+    }
+
+    pub fn poll_descriptor(&self) -> pollfd {
+        pollfd { fd: self.wakeup.as_raw_fd(), events: Flags::IN.bits(), revents: 0 }
+    }
+
+    pub fn pending(&mut self) -> Vec<SignalAction> {
+        let mut buf = [0u8; 16];
+        while let Ok(n) = self.wakeup.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+
+        let mut result = Vec::new();
+        if self.reload.swap(false, Ordering::Relaxed) {
+            result.push(SignalAction::Reload);
+        }
+        if self.quit.swap(false, Ordering::Relaxed) {
+            result.push(SignalAction::Quit);
+        }
+        result
+    }
+}
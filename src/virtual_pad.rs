@@ -0,0 +1,342 @@
+extern crate libc;
+
+use std::io::{ self, Write };
+use std::mem;
+use std::time::Instant;
+use anyhow::Result;
+use alsa::poll::{ pollfd, Flags };
+use crate::alsa_midi::{ Event, PadControl };
+
+/*
+ * Puts the terminal into a mode where keys and mouse clicks arrive straight away, and puts it
+ * back on drop.
+ */
+struct Terminal {
+    saved: libc::termios
+}
+
+impl Terminal {
+    fn enter() -> Result<Terminal> {
+        let saved = unsafe {
+            let mut term: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut term) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            let saved = term;
+            // Ctrl-C still sends SIGINT.
+            term.c_lflag &= !(libc::ICANON | libc::ECHO);
+            term.c_cc[libc::VMIN] = 0;
+            term.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &term) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            saved
+        };
+        // Alternate screen, hidden cursor, SGR mouse reporting.
+        print!("\x1b[?1049h\x1b[?25l\x1b[?1000h\x1b[?1006h\x1b[2J");
+        io::stdout().flush()?;
+        Ok(Terminal { saved })
+    }
+
+    fn read(&self) -> Vec<u8> {
+        let mut buf = [0u8; 256];
+        let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n > 0 {
+            buf[..n as usize].to_vec()
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?1006l\x1b[?1000l\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved);
+        }
+    }
+}
+
+// Where the pad is drawn: the Numbers row on this line, the grid below it, three columns a cell.
+const TOP_ROW: u16 = 2;
+const CELL_WIDTH: u16 = 3;
+// How long each buffer is shown for while flashing, in milliseconds.
+const FLASH_PERIOD: u128 = 250;
+
+/*
+ * A Launchpad Mini in software. It speaks the same MIDI as the real one, double buffering,
+ * flashing and rapid update included, so it sits under LaunchPad in place of the sequencer.
+ * Without a terminal it runs headless: nothing is drawn and there is no input.
+ */
+pub struct VirtualPad {
+    // Two buffers of velocities in rapid update order: the grid by rows, the Letters, the Numbers.
+    buffers: [[u8; 80]; 2],
+    displayed: usize,
+    updating: usize,
+    // The next LED a rapid update message sets.
+    rapid: usize,
+    // Whether the pad flips between the buffers by itself, and when it started.
+    flash: Option<Instant>,
+    terminal: Option<Terminal>,
+    // The cell under the keyboard cursor. x 8 is the Letters column, y -1 the Numbers row.
+    cursor: (i8, i8),
+    input: Vec<u8>,
+    releases: Vec<Event>,
+    dirty: bool
+}
+
+impl VirtualPad {
+    pub fn new(interactive: bool) -> Result<VirtualPad> {
+        let terminal = if interactive && unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+            Some(Terminal::enter()?)
+        } else {
+            None
+        };
+        Ok(VirtualPad {
+            buffers: [[0; 80]; 2],
+            displayed: 0,
+            updating: 0,
+            rapid: 0,
+            flash: None,
+            terminal,
+            cursor: (0, 0),
+            input: Vec::new(),
+            releases: Vec::new(),
+            dirty: true
+        })
+    }
+
+    fn button_event(x: i8, y: i8, pressed: bool) -> Option<Event> {
+        let value = if pressed { 127 } else { 0 };
+        match y {
+            -1 if (0..8).contains(&x) => Some(Event::Control { channel: 0, param: 0x68 + x as u32, value }),
+            0..=7 if (0..9).contains(&x) => Some(Event::Note { channel: 0, note: (x + y * 16) as u8, velocity: value as u8 }),
+            _ => None
+        }
+    }
+
+    /*
+     * A press that is released again on the next poll, for keys, which have no release.
+     */
+    fn tap(&mut self, x: i8, y: i8, result: &mut Vec<Event>) {
+        if let Some(ev) = VirtualPad::button_event(x, y, true) {
+            result.push(ev);
+            self.releases.extend(VirtualPad::button_event(x, y, false));
+        }
+    }
+
+    fn mouse_cell(col: u16, row: u16) -> (i8, i8) {
+        let x = ((col.max(1) - 1) / CELL_WIDTH).min(100) as i8;
+        let y = (row as i32 - TOP_ROW as i32 - 1).clamp(-100, 100) as i8;
+        (x, y)
+    }
+
+    /*
+     * Parses as much of the input as possible, leaving any partial escape sequence behind.
+     */
+    fn parse_input(&mut self) -> Vec<Event> {
+        let mut result = Vec::new();
+        let input = mem::take(&mut self.input);
+        let mut i = 0;
+        while i < input.len() {
+            let rest = &input[i..];
+            if rest[0] != 0x1b {
+                match rest[0] {
+                    b' ' | b'\n' => {
+                        let (x, y) = self.cursor;
+                        self.tap(x, y, &mut result);
+                    },
+                    b'q' => self.tap(7, -1, &mut result),
+                    _ => {}
+                }
+                i += 1;
+                continue;
+            }
+            if rest.len() < 3 {
+                self.input = rest.to_vec();
+                break;
+            }
+            if rest[1] != b'[' {
+                i += 1;
+                continue;
+            }
+            match rest[2] {
+                b'A' => self.cursor.1 = (self.cursor.1 - 1).max(-1),
+                b'B' => self.cursor.1 = (self.cursor.1 + 1).min(7),
+                b'C' => self.cursor.0 = (self.cursor.0 + 1).min(8),
+                b'D' => self.cursor.0 = (self.cursor.0 - 1).max(0),
+                b'<' => {
+                    // SGR mouse: ESC [ < button ; col ; row (M press | m release)
+                    let end = match rest.iter().position(|c| *c == b'M' || *c == b'm') {
+                        Some(end) => end,
+                        None => {
+                            self.input = rest.to_vec();
+                            break;
+                        }
+                    };
+                    let fields: Vec<u16> = String::from_utf8_lossy(&rest[3..end]).split(';')
+                        .filter_map(|f| f.parse().ok()).collect();
+                    if fields.len() == 3 && fields[0] == 0 {
+                        let (x, y) = VirtualPad::mouse_cell(fields[1], fields[2]);
+                        result.extend(VirtualPad::button_event(x, y, rest[end] == b'M'));
+                    }
+                    i += end + 1;
+                    self.dirty = true;
+                    continue;
+                },
+                _ => {}
+            }
+            self.dirty = true;
+            i += 3;
+        }
+        result
+    }
+
+    fn note_index(note: u8) -> Option<usize> {
+        let (x, y) = ((note % 16) as usize, (note / 16) as usize);
+        match x {
+            _ if y >= 8 => None,
+            0..=7 => Some(x + y * 8),
+            8 => Some(64 + y),
+            _ => None
+        }
+    }
+
+    /*
+     * Sets an LED the way the velocity flags say: copy writes both buffers, clear blanks the
+     * other one.
+     */
+    fn set_led(&mut self, index: usize, velocity: u8) {
+        let colour = velocity & 0x33;
+        let other = 1 - self.updating;
+        self.buffers[self.updating][index] = colour;
+        if velocity & 0x04 != 0 {
+            self.buffers[other][index] = colour;
+        } else if velocity & 0x08 != 0 {
+            self.buffers[other][index] = 0;
+        }
+    }
+
+    /*
+     * What an LED shows, by its index in rapid update order: its colour bits, and whether it
+     * flashes between them and off.
+     */
+    pub fn led(&self, index: usize) -> (u8, bool) {
+        let shown = self.buffers[self.displayed][index];
+        let hidden = self.buffers[1 - self.displayed][index];
+        match self.flash {
+            Some(_) if shown != hidden => (shown | hidden, true),
+            _ => (shown, false)
+        }
+    }
+
+    fn buffer_control(&mut self, value: i32) {
+        if value == 0 {
+            self.buffers = [[0; 80]; 2];
+            self.displayed = 0;
+            self.updating = 0;
+            self.flash = None;
+            return;
+        }
+        self.displayed = (value & 0x01) as usize;
+        self.updating = ((value >> 2) & 0x01) as usize;
+        self.flash = if value & 0x08 == 0 { None } else { Some(self.flash.unwrap_or_else(Instant::now)) };
+        if value & 0x10 != 0 {
+            self.buffers[self.updating] = self.buffers[self.displayed];
+        }
+    }
+
+    fn cell(&self, velocity: u8, round: bool, selected: bool) -> String {
+        let red = (velocity & 0x3) as u32 * 85;
+        let green = ((velocity >> 4) & 0x3) as u32 * 85;
+        let (left, right) = if selected { ('[', ']') } else { (' ', ' ') };
+        if round {
+            format!("\x1b[38;2;{};{};0m{}\u{25cf}{}\x1b[0m", red.max(40), green.max(40), left, right)
+        } else {
+            format!("\x1b[48;2;{};{};0m{} {}\x1b[0m", red.max(25), green.max(25), left, right)
+        }
+    }
+
+    fn render(&mut self) -> Result<()> {
+        if self.terminal.is_none() || !self.dirty {
+            return Ok(());
+        }
+        self.dirty = false;
+        let mut out = String::new();
+        out.push_str("\x1b[H\x1b[1mBlinkenpad virtual pad\x1b[0m\r\n");
+        let leds = &self.buffers[self.displayed];
+        for n in 0..8 {
+            out.push_str(&self.cell(leds[72 + n], true, self.cursor == (n as i8, -1)));
+        }
+        out.push_str("\r\n");
+        for y in 0..8i8 {
+            for x in 0..9i8 {
+                let index = if x == 8 { 64 + y as usize } else { (x + y * 8) as usize };
+                out.push_str(&self.cell(leds[index], x == 8, self.cursor == (x, y)));
+            }
+            out.push_str("\r\n");
+        }
+        out.push_str("\r\nArrows move, space presses, mouse clicks, q quits.\r\n");
+        let mut stdout = io::stdout();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+impl PadControl for VirtualPad {
+    fn process_in(&mut self, events: Vec<Event>) -> Result<()> {
+        for event in events {
+            match event {
+                // Rapid update: two LEDs a message, in order. Anything else starts it again.
+                Event::Note { channel: 2, note, velocity } => {
+                    for v in [note, velocity] {
+                        if self.rapid < 80 {
+                            self.set_led(self.rapid, v);
+                            self.rapid += 1;
+                        }
+                    }
+                    self.dirty = true;
+                    continue;
+                },
+                Event::Note { note, velocity, .. } => if let Some(index) = VirtualPad::note_index(note) {
+                    self.set_led(index, velocity);
+                },
+                Event::Control { channel: 0, param: 0, value } => self.buffer_control(value),
+                Event::Control { param, value, .. } if (0x68..0x70).contains(&param) =>
+                    self.set_led(72 + (param - 0x68) as usize, value as u8),
+                _ => {}
+            }
+            self.rapid = 0;
+            self.dirty = true;
+        }
+        self.render()
+    }
+
+    fn process_out(&mut self) -> Result<Vec<Event>> {
+        let mut result = mem::take(&mut self.releases);
+        if let Some(terminal) = &self.terminal {
+            let bytes = terminal.read();
+            self.input.extend(bytes);
+        }
+        result.append(&mut self.parse_input());
+        if let Some(started) = self.flash {
+            let displayed = (started.elapsed().as_millis() / FLASH_PERIOD % 2) as usize;
+            if displayed != self.displayed {
+                self.displayed = displayed;
+                self.dirty = true;
+            }
+        }
+        self.render()?;
+        Ok(result)
+    }
+
+    fn poll_descriptors(&self) -> Result<Vec<pollfd>> {
+        Ok(match self.terminal {
+            Some(_) => vec![pollfd { fd: libc::STDIN_FILENO, events: Flags::IN.bits(), revents: 0 }],
+            None => Vec::new()
+        })
+    }
+}
@@ -1,50 +1,180 @@
-
 extern crate libxdo;
 
 use libxdo::XDo;
-use crate::blinken::PluginArea;
+use crate::blinken::{ PluginArea, grid_only };
+use crate::events::PadEvent;
 use crate::launchpad::{PadLocation, PadColour};
-use anyhow::{ Result, Error };
+use anyhow::Result;
+
+/*
+ * What a button does. Held keys and clicks go down with the button and up when it is released,
+ * e.g. for modifiers or dragging. Moves and scrolls that repeat carry on every tick while the
+ * button is held, and moves get faster the longer it is.
+ */
+pub enum XdoAction {
+    Keys { key: String, hold: bool },
+    Click { button: i32, hold: bool },
+    DoubleClick { button: i32 },
+    MoveTo { x: i32, y: i32, screen: i32 },
+    MoveBy { x: i32, y: i32, repeat: bool },
+    DragTo { button: i32, x: i32, y: i32, screen: i32 },
+    DragBy { button: i32, x: i32, y: i32 },
+    // In clicks of the wheel; positive y scrolls down and positive x right.
+    Scroll { x: i32, y: i32, repeat: bool }
+}
+
+/*
+ * A key sequence or mouse action on a button.
+ */
+pub struct XdoKey {
+    pub x: u8,
+    pub y: u8,
+    pub colour: PadColour,
+    pub action: XdoAction
+}
+
+// Repeated moves go this many times further for each tick the button is held, up to MAX_SPEED
+// times the first step.
+const ACCELERATION: u32 = 2;
+const MAX_SPEED: u32 = 16;
 
 pub struct XdoPlugin {
     xdo: XDo,
-    colours: Vec<PadColour>,
-    keys: Vec<String>
+    keys: Vec<XdoKey>,
+    // Held keys and buttons that are currently down, by index into keys.
+    down: Vec<usize>
 }
 
 impl XdoPlugin {
-    pub fn new(colours: Vec<PadColour>, keys: Vec<String>) -> Result<XdoPlugin> {
+    pub fn new(keys: Vec<XdoKey>) -> Result<XdoPlugin> {
         Ok(XdoPlugin {
             xdo: XDo::new(None)?,
-            colours: colours,
-            keys: keys
+            keys,
+            down: Vec::new()
         })
     }
+
+    fn find_key(&self, x: u8, y: u8) -> Option<usize> {
+        self.keys.iter().position(|k| k.x == x && k.y == y)
+    }
+
+    fn press(&mut self, i: usize) -> Result<()> {
+        let xdo = &self.xdo;
+        match &self.keys[i].action {
+            XdoAction::Keys { key, hold: true } => {
+                xdo.send_keysequence_down(key, 0)?;
+                self.down.push(i);
+            },
+            XdoAction::Keys { key, hold: false } => xdo.send_keysequence(key, 0)?,
+            XdoAction::Click { button, hold: true } => {
+                xdo.mouse_down(*button)?;
+                self.down.push(i);
+            },
+            XdoAction::Click { button, hold: false } => xdo.click(*button)?,
+            XdoAction::DoubleClick { button } => {
+                xdo.click(*button)?;
+                xdo.click(*button)?;
+            },
+            XdoAction::MoveTo { x, y, screen } => xdo.move_mouse(*x, *y, *screen)?,
+            XdoAction::MoveBy { x, y, .. } => xdo.move_mouse_relative(*x, *y)?,
+            XdoAction::DragTo { button, x, y, screen } => {
+                xdo.mouse_down(*button)?;
+                xdo.move_mouse(*x, *y, *screen)?;
+                xdo.mouse_up(*button)?;
+            },
+            XdoAction::DragBy { button, x, y } => {
+                xdo.mouse_down(*button)?;
+                xdo.move_mouse_relative(*x, *y)?;
+                xdo.mouse_up(*button)?;
+            },
+            XdoAction::Scroll { x, y, .. } => XdoPlugin::scroll(xdo, *x, *y)?
+        }
+        Ok(())
+    }
+
+    /*
+     * Carries on with a repeating move or scroll, with the step grown by how long the button
+     * has been held.
+     */
+    fn repeat(&self, i: usize, held: u32) -> Result<()> {
+        let speed = (1 + held * ACCELERATION).min(MAX_SPEED) as i32;
+        match &self.keys[i].action {
+            XdoAction::MoveBy { x, y, repeat: true } => self.xdo.move_mouse_relative(x * speed, y * speed)?,
+            // Scrolling by a whole wheel click at a time is quick enough without speeding up.
+            XdoAction::Scroll { x, y, repeat: true } => XdoPlugin::scroll(&self.xdo, *x, *y)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /*
+     * Wheels are buttons 4 and 5 for up and down, and 6 and 7 for left and right.
+     */
+    fn scroll(xdo: &XDo, x: i32, y: i32) -> Result<()> {
+        for (amount, back, forward) in [(y, 4, 5), (x, 6, 7)] {
+            for _ in 0..amount.abs() {
+                xdo.click(if amount < 0 { back } else { forward })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn release(&self, i: usize) -> Result<()> {
+        match &self.keys[i].action {
+            XdoAction::Keys { key, .. } => self.xdo.send_keysequence_up(key, 0)?,
+            XdoAction::Click { button, .. } => self.xdo.mouse_up(*button)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /*
+     * Lets go of any held keys and buttons, so that e.g. a modifier isn't left down when its
+     * button can no longer be released.
+     */
+    fn release_all(&mut self) -> Result<()> {
+        for i in std::mem::take(&mut self.down) {
+            self.release(i)?;
+        }
+        Ok(())
+    }
 }
 
 impl PluginArea for XdoPlugin {
-    fn process_input(&mut self, _tick: u32, set_values: &Vec<PadLocation>) -> Result<()> {
-        for value in set_values {
-            match value {
-                PadLocation::OnPad(x,y) => match y {
-                    0 => self.xdo.send_keysequence(&self.keys[*x as usize], 0)?,
-                    _ => Err(Error::msg("Only supports one line"))?,
+    fn process_input(&mut self, _tick: u32, events: &Vec<PadEvent>) -> Result<()> {
+        for event in events {
+            match event {
+                PadEvent::Press(PadLocation::OnPad(x,y)) => if let Some(i) = self.find_key(*x, *y) {
+                    self.press(i)?;
+                },
+                PadEvent::Held(PadLocation::OnPad(x,y), held) => if let Some(i) = self.find_key(*x, *y) {
+                    self.repeat(i, *held)?;
                 },
-                PadLocation::Letters(_) => panic!("Invalid letter pad press in plugin"),
-                PadLocation::Numbers(_) => panic!("Invalid number pad press in plugin"),
+                PadEvent::Release(PadLocation::OnPad(x,y), _) => if let Some(i) = self.find_key(*x, *y) {
+                    if self.down.contains(&i) {
+                        self.down.retain(|d| *d != i);
+                        self.release(i)?;
+                    }
+                },
+                other => grid_only(other)?
             }
         }
         Ok(())
     }
 
     fn process_output(&mut self, _tick: u32) -> Result<Vec<(PadLocation, PadColour)>> {
-        let mut result = Vec::new();
-        let mut x = 0;
-        for c in &self.colours {
-            result.push((PadLocation::on_pad(x, 0), *c));
-            x += 1;
-        }
-        Ok(result)
+        Ok(self.keys.iter().map(|k| (PadLocation::on_pad(k.x, k.y), k.colour)).collect())
+    }
+
+    fn on_hide(&mut self) -> Result<()> {
+        self.release_all()
     }
-}
 
+    fn on_device_lost(&mut self) -> Result<()> {
+        self.release_all()
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        self.release_all()
+    }
+}